@@ -30,6 +30,7 @@ parser.add_argument('-l','--language', action='store_true', help="Remove docs th
 parser.add_argument('-r','--robots', type=str, required=False, help="List of robots.txt disallowed urls")
 parser.add_argument('-c','--crawl_names', type=str, required=False, help="List of warc file name patters by crawl")
 parser.add_argument('-z','--cjk', action='store_true', help="Process CJK language")
+parser.add_argument('-n','--no_monofixer', action='store_true', help="Do not apply monofixer, text has already been fixed by annotator")
 
 args = parser.parse_args()
 if args.all:
@@ -195,6 +196,8 @@ def filter_doc(args, doc):
     return "keep"
 
 # Look for PII, return matched ranges
+# ranges are Python string positions, so offsets are in Unicode code points, not bytes
+# Rust consumers must convert them before slicing (see utils/src/pii.rs)
 def pii_multi(text):
     matches = proc(text)
     return list(sorted((i.pos, i.pos + len(i.value)) for i in matches))
@@ -261,8 +264,11 @@ def get_lang_wds(langcode_script):
 for line in sys.stdin:
     doc = orjson.loads(line)
     #doc["id"] = xxh128_hexdigest(doc["f"] + doc["u"] + doc["ts"])
-    doc['text'] = monofixer(doc['text'])
-    doc["filter"] = filter_doc(args, doc)
+    if not args.no_monofixer:
+        doc['text'] = monofixer(doc['text'])
+    # keep the reason if the document was already filtered by annotator (e.g. secrets)
+    if doc.get("filter", "keep") == "keep":
+        doc["filter"] = filter_doc(args, doc)
     doc["pii"] = pii_multi(doc["text"])
     seg_langs = doc["seg_langs_openlid_v3"]
     doc_lang = doc["openlid_v3"]["lang"][0]
@@ -1,25 +1,30 @@
-use std::collections::HashMap;
 use std::fs;
-use std::io;
-use std::io::BufRead;
-use std::sync::mpsc::sync_channel;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use aho_corasick::AhoCorasick;
 use clap::Parser;
 use env_logger::Env;
 use fst::Set;
-use itertools::Itertools;
-use log::{debug, info};
+use log::{debug, info, warn};
 use memmap2::Mmap;
 use rayon::prelude::*;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
+use serde_json::json;
+use zstd::stream::write::Encoder;
 
 use heli_otr::identifier::Identifier;
 use heli_otr::{load_models, pythonpath};
 
+use monotextor_utils::document::Document;
+use monotextor_utils::input::InputSource;
+use monotextor_utils::onerror::{BadRecord, ErrorArgs, ErrorHandler};
+use monotextor_utils::robots::{parse_timestamp, RobotsArgs, RobotsIndex};
+use monotextor_utils::secrets::{SecretMatch, SecretsAction, SecretsMatcher};
+use monotextor_utils::splice::RawDocument;
+use monotextor_utils::split::{Output, ZSplit};
+use monotextor_utils::textfix::TextFixer;
+use monotextor_utils::url::{disallowed_key, url_key};
+
 #[derive(Parser)]
 #[command(
     version,
@@ -30,62 +35,108 @@ struct Args {
     modelpath: Option<String>,
     #[arg(short, help = "Add robotstxt disallowed info with an FST index")]
     disallowed_index: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "disallowed_index",
+        help = "Add robotstxt disallowed info evaluating each url with a robots-compile index"
+    )]
+    robots_index: Option<String>,
+    #[command(flatten)]
+    robots: RobotsArgs,
     #[arg(
         short,
         help = "Remove documents that contain any of these list of secrets"
     )]
     secrets_list: Option<String>,
-}
+    #[arg(
+        short = 'b',
+        long,
+        help = "Detect secrets with the built-in rules (API keys, private keys, JWTs...)"
+    )]
+    builtin_secrets: bool,
+    #[arg(value_enum, long, default_value_t = SecretsAction::Drop,
+          help = "What to do with documents that contain secrets")]
+    secrets_action: SecretsAction,
+    #[arg(
+        long,
+        help = "Write id, url and matched pattern of documents with secrets to this zstd file"
+    )]
+    secrets_report: Option<String>,
+    #[arg(
+        long,
+        requires = "secrets_report",
+        help = "Write the full documents with secrets to the report, instead of id and url"
+    )]
+    secrets_report_docs: bool,
+    #[arg(
+        short = 'p',
+        long,
+        help = "Check that pii spans of each document are valid character offsets of the input text. \
+                Spans are added by annotate.py after annotator in 20.processing, \
+                so run it on the annotated output, e.g. 'zstdcat batch.zst | annotator -p >/dev/null'"
+    )]
+    validate_pii: bool,
+    #[arg(
+        short = 'f',
+        long,
+        help = "Fix text (monofixer) for this language before language identification. \
+                Only the generic, Romanian, Cyrillic and Greek replacements of bifixer are ported"
+    )]
+    fix_lang: Option<String>,
 
-#[derive(Serialize, Deserialize)]
-struct Lang {
-    lang: Vec<String>,
-    prob: Vec<f32>,
-}
+    #[arg(
+        long,
+        default_value_t = 50000,
+        help = "Number of documents to be processed at a time"
+    )]
+    batch_size: usize,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Number of processing threads. By default, use all available cores"
+    )]
+    threads: usize,
+    #[arg(
+        short,
+        long,
+        help = "Write zstd compressed output splits with this prefix, instead of stdout. \
+                Can also be a name template like 'batch_{N}.jsonl.zst' or '{key}_{N:4}.jsonl.zst'"
+    )]
+    output_prefix: Option<String>,
+    #[arg(
+        long,
+        requires = "output_prefix",
+        help = "Uncompressed size per each output split in GB. By default, do not split"
+    )]
+    split_size: Option<usize>,
+    #[arg(
+        long,
+        requires = "output_prefix",
+        help = "Maximum number of documents per each output split"
+    )]
+    split_docs: Option<usize>,
+    #[arg(
+        long,
+        requires = "output_prefix",
+        help = "Write a different set of splits for each value of this field (e.g. crawl_id), \
+                the output name template needs '{key}'"
+    )]
+    split_key: Option<String>,
+    #[arg(long, default_value_t = 10, requires = "output_prefix")]
+    compression_level: i32,
+    #[arg(long, default_value_t = 4, requires = "output_prefix")]
+    compression_threads: u32,
+
+    #[command(flatten)]
+    errors: ErrorArgs,
 
-// Define a document struct
-// this however, it is different from the one in lib.rs
-// because documents may have different fields at each stage
-#[derive(Serialize, Deserialize)]
-struct Document {
-    text: String,
-    xml: Option<String>,
-    md: Option<String>,
-    f: String,
-    o: usize,
-    s: usize,
-    rs: usize,
-    u: String,
-    c: String,
-    ts: String,
-    de: String,
-    id: String,
-    #[serde(alias = "openlid-v2")]
-    openlid_v2: Lang,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    allowed: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    htmllang: Option<Vec<String>>,
-    #[serde(alias = "glotlid-v3")]
-    glotlid_v3: Lang,
-    #[serde(alias = "openlid-v3")]
-    openlid_v3: Lang,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    seg_langs_openlid_v3: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    crawl_id: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    cluster_size: Option<usize>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pii: Option<Vec<(usize, usize)>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    filter: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    doc_scores: Option<Vec<f32>>,
-    #[serde(rename = "web-register", skip_serializing_if = "Option::is_none")]
-    web_register: Option<HashMap<String, f32>>,
+    #[arg(help = "Input jsonl files, globs or directories, compressed or not. \
+                  If not provided, read from stdin")]
+    inputs: Vec<String>,
 }
 
+const OUTPUT_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
 fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     info!("Started");
@@ -104,113 +155,237 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     } else {
         index_main = None;
     }
-    // remove url http and ww prefix
-    let url_prefix_re = Arc::new(Regex::new(r"^(https?://)?(www\.)?(.*)$")?);
+    let robots_index = match &args.robots_index {
+        Some(path) => Some(RobotsIndex::open(path)?.with_args(&args.robots)),
+        None => None,
+    };
 
     let secrets_matcher: Option<_>;
-    if let Some(filename) = args.secrets_list {
-        let file_read =
-            io::BufReader::new(fs::File::open(&filename).expect("Secrets list does not exist"));
-        let patterns: Vec<_> = file_read.lines().map(|line| line.unwrap()).collect();
-        secrets_matcher = Some(AhoCorasick::new(&patterns)?);
+    if args.builtin_secrets || args.secrets_list.is_some() {
+        secrets_matcher = Some(SecretsMatcher::new(
+            args.builtin_secrets,
+            args.secrets_list.as_deref(),
+        )?);
     } else {
         secrets_matcher = None;
     }
 
+    let mut secrets_report = match &args.secrets_report {
+        Some(filename) => Some(BufWriter::new(
+            Encoder::new(fs::File::create(filename)?, 3)?.auto_finish(),
+        )),
+        None => None,
+    };
+
+    let text_fixer = args.fix_lang.as_deref().map(TextFixer::new);
+
     // Load model and create atomic references
     // so only one model is loaded, then shared with each thread
     let (charmodel, wordmodel) = load_models(&modelpath);
     let charmodelref = Arc::new(charmodel);
     let wordmodelref = Arc::new(wordmodel);
 
-    let batch_size = 50000;
-    let stdin = io::stdin();
-    let (sender, receiver) = sync_channel(1);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build_global()?;
+
+    let mut output = match &args.output_prefix {
+        Some(prefix) => Output::Split(ZSplit::new(
+            prefix,
+            args.split_size
+                .map_or(usize::MAX, |size| size * 1_000_000_000),
+            args.compression_level,
+            args.compression_threads,
+            OUTPUT_BUFFER_SIZE,
+        )?
+        .with_max_docs(args.split_docs.unwrap_or(usize::MAX))),
+        None => Output::stdout(OUTPUT_BUFFER_SIZE),
+    };
+
+    let errors = ErrorHandler::new(&args.errors)?;
+    let input = InputSource::new(&args.inputs)?;
 
     let mut num_kept = 0_usize;
-    let num_read = Arc::new(Mutex::new(0_usize));
-    let counter = Arc::clone(&num_read);
-
-    // do the stdin read and batching in a separated thread
-    let read_thread = thread::spawn(move || {
-        // Read from stdin in batches and process them
-        for batch_result in &stdin.lock().lines().chunks(batch_size) {
-            let batch: Vec<String> = batch_result
-                .map(|line| line.expect("Error decoding line"))
-                .collect();
-            let mut count = counter.lock().unwrap();
-            *count += batch.len();
-            sender.send(batch).unwrap();
-        }
-    });
+    let mut num_read = 0_usize;
+    let num_invalid_pii = AtomicUsize::new(0);
 
-    while let Ok(batch) = receiver.recv() {
+    // the input read and batching is done in a separated thread
+    for batch in input.batches(args.batch_size) {
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                // same as a bad record, keep what was processed before
+                output.abort()?;
+                errors.finish()?;
+                if let Some(writer) = &mut secrets_report {
+                    writer.flush()?;
+                }
+                return Err(e.into());
+            }
+        };
+        num_read += batch.lines.len();
+        let source = input.name(batch.file_idx);
+        let first_line = batch.first_line;
         // process every batch in parallel
         // parse json document
         // add segment level langid
-        let docs: Vec<_> = batch
+        let docs = batch
+            .lines
             .par_iter()
-            .filter_map(|line: &String| {
+            .enumerate()
+            .map(|(i, line)| {
                 // each thread will create the mutable part of the identifier
                 // and share with the main thread the language model, which is immutable
                 let mut detector =
                     Identifier::new(Arc::clone(&charmodelref), Arc::clone(&wordmodelref));
-                let mut doc: Document =
-                    serde_json::from_str(line.as_str()).expect("Error parsing JSON document");
+                let line_num = first_line + i;
+                let Some(line_str) = errors.decode(source, line_num, line)? else {
+                    return Ok((None, None));
+                };
+                // fields are read from the shared document model, but only the ones
+                // modified are written back, the rest of the line is kept as is
+                let parsed: Document = match serde_json::from_str(line_str) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        errors.bad_record(source, line_num, line, e)?;
+                        return Ok((None, None));
+                    }
+                };
+                let mut doc = match RawDocument::parse(line_str) {
+                    Ok(doc) => doc,
+                    Err(e) => {
+                        errors.bad_record(source, line_num, line, e)?;
+                        return Ok((None, None));
+                    }
+                };
+                let mut text = parsed.text.clone();
+                let id = parsed.id.clone();
+                let u = parsed.u.clone();
 
-                // Documents that contain secrets are discarded
+                // Documents that contain secrets are discarded or annotated
+                // and reported if requested, so false positives can be audited
+                let mut report = None;
                 if let Some(matcher) = &secrets_matcher {
-                    if matcher.is_match(&doc.text) {
-                        return None;
+                    if let Some(secret) = matcher.find(&text) {
+                        if args.secrets_report_docs {
+                            report = Some(line_str.to_string());
+                        } else {
+                            let record = match secret {
+                                SecretMatch::Literal(idx) => {
+                                    json!({"id": id, "u": u, "literal": idx})
+                                }
+                                SecretMatch::Rule(name) => {
+                                    json!({"id": id, "u": u, "rule": name})
+                                }
+                            };
+                            report = Some(record.to_string());
+                        }
+                        match args.secrets_action {
+                            SecretsAction::Drop => return Ok((None, report)),
+                            SecretsAction::Annotate => {
+                                doc.set_raw("filter", "\"secrets\"".to_string())
+                            }
+                        }
+                    }
+                }
+
+                // spans refer to the input text, check them before it is fixed
+                // a pii field that is not a list of spans is invalid too
+                if args.validate_pii {
+                    if let Err(e) = parsed.pii_byte_spans() {
+                        warn!("Invalid pii in document {}: {}", parsed.id_or_empty(), e);
+                        num_invalid_pii.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+
+                // fix text before anything else that depends on it
+                if let Some(fixer) = &text_fixer {
+                    let fixed = fixer.fix(&text);
+                    if fixed != text {
+                        doc.set("text", &fixed).unwrap();
+                        text = fixed;
                     }
                 }
 
                 // identify each segment (splitting by endlines) in the document text
                 // add the predictions to seg_langs array in the json
-                let _ = doc.seg_langs_openlid_v3.insert(Vec::new());
-                for line in doc.text.lines() {
-                    let pred = detector.identify(&line).0.to_string();
-                    doc.seg_langs_openlid_v3.as_mut().unwrap().push(pred);
+                let mut seg_langs = Vec::new();
+                for line in text.lines() {
+                    seg_langs.push(detector.identify(line).0.to_string());
                 }
+                doc.set("seg_langs_openlid_v3", &seg_langs).unwrap();
 
-                if let Some(index) = &index_main {
-                    // Remove http://www prefix
-                    let url = url_prefix_re
-                        .captures(&doc.u)
-                        .expect("Could not parse url")
-                        .get(3)
-                        .expect("Could not obtain capture group 3 for url")
-                        .as_str();
-
+                // documents without url (e.g. external contributions) are not annotated
+                if let (Some(index), Some(u)) = (&index_main, &u) {
                     // Search in the fst if we have the url
-                    // this time exact match, as we have full urls in the index
-                    if index.contains(url) {
-                        doc.allowed = Some(false);
-                    } else {
-                        doc.allowed = Some(true);
-                    }
+                    // this time exact match, as we have full normalized urls in the index
+                    let key = url_key(u);
+                    doc.set("allowed", &!index.contains(disallowed_key(&key))).unwrap();
+                } else if let (Some(robots), Some(u)) = (&robots_index, &u) {
+                    // evaluate the url with the robots.txt rules of its origin
+                    // in effect when the document was crawled
+                    let ts = parsed.ts.as_deref().and_then(parse_timestamp);
+                    let allowed = robots.is_allowed(u, ts);
+                    doc.set("allowed", &allowed).unwrap();
                 }
 
-                Some(doc)
+                // value of the split key, only chars that are safe for a file name are kept
+                let key = match &args.split_key {
+                    Some(field) => doc
+                        .get::<String>(field)
+                        .and_then(Result::ok)
+                        .unwrap_or_else(|| String::from("unknown"))
+                        .replace(|c: char| !c.is_alphanumeric() && c != '-' && c != '_', "_"),
+                    None => String::new(),
+                };
+
+                Ok((Some((key, doc.to_json().unwrap())), report))
             })
-            .collect();
+            .collect::<Result<Vec<_>, BadRecord>>();
+        let docs = match docs {
+            Ok(docs) => docs,
+            Err(e) => {
+                // keep the output and report of the batches processed before the bad record
+                output.abort()?;
+                errors.finish()?;
+                if let Some(writer) = &mut secrets_report {
+                    writer.flush()?;
+                }
+                return Err(e.into());
+            }
+        };
 
-        // serialize modified documents and print them to stdout
-        for doc in docs {
-            num_kept += 1;
-            println!("{}", serde_json::to_string(&doc).unwrap());
+        // serialize modified documents and write them to the output
+        for (doc, report) in docs {
+            if let (Some(writer), Some(record)) = (&mut secrets_report, report) {
+                writer.write_all(record.as_bytes())?;
+                writer.write_all(b"\n")?;
+            }
+            if let Some((key, doc)) = doc {
+                num_kept += 1;
+                output.write_line_key(&key, doc.as_bytes())?;
+            }
         }
     }
+    output.finish()?;
+    errors.finish()?;
+    if let Some(writer) = &mut secrets_report {
+        writer.flush()?;
+    }
 
-    read_thread.join().unwrap();
-    let num_read_final = *num_read.lock().unwrap();
-    info!("{} documents read", num_read_final);
-    let removed = num_read_final - num_kept;
+    info!("{} documents read", num_read);
+    let removed = num_read - num_kept;
     info!(
         "{} documents removed ({:.2} %)",
         removed,
-        removed as f32 / (num_read_final as f32) * 100.0
+        removed as f32 / (num_read as f32) * 100.0
     );
+    if args.validate_pii {
+        info!(
+            "{} documents with invalid pii spans",
+            num_invalid_pii.load(Ordering::Relaxed)
+        );
+    }
     info!("Finished");
     Ok(())
 }
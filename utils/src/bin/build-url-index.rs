@@ -0,0 +1,93 @@
+/* Build the FST index of the normalized urls of a collection, to be searched by robots-filter
+ * Urls are read from the 'u' field of JSONL documents (e.g. the metadata.zst of each batch),
+ * normalized in the same way as the robots.txt patterns and sorted with an external merge sort,
+ * so the whole collection does not need to fit in memory.
+ */
+use std::time::Instant;
+
+use clap::Parser;
+use env_logger::Env;
+use log::info;
+use rayon::prelude::*;
+
+use monotextor_utils::extsort::{write_set, SortArgs};
+use monotextor_utils::input::InputSource;
+use monotextor_utils::onerror::{BadRecord, ErrorArgs, ErrorHandler};
+use monotextor_utils::splice::RawDocument;
+use monotextor_utils::url::url_key;
+use monotextor_utils::utils::memory_usage;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Build an FST index of the normalized urls of JSONL documents"
+)]
+struct Args {
+    #[arg(help = "Output FST path")]
+    output: String,
+    #[arg(help = "JSONL files with 'u' field, globs or directories, compressed or not")]
+    files: Vec<String>,
+    #[command(flatten)]
+    sort: SortArgs,
+    #[command(flatten)]
+    errors: ErrorArgs,
+}
+
+const BATCH_SIZE: usize = 100_000;
+
+fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let now = Instant::now();
+    let args = Args::parse();
+    let input = InputSource::new(&args.files)?;
+    let errors = ErrorHandler::new(&args.errors)?;
+    let mut sorter = args.sort.sorter(&args.output);
+    let mut num_read = 0;
+
+    info!("Reading urls");
+    for batch in input.batches(BATCH_SIZE) {
+        let batch = batch?;
+        num_read += batch.lines.len();
+        let source = input.name(batch.file_idx);
+        let urls = batch
+            .lines
+            .par_iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let line_num = batch.first_line + i;
+                let Some(line_str) = errors.decode(source, line_num, line)? else {
+                    return Ok(None);
+                };
+                match RawDocument::parse(line_str).map(|doc| doc.get::<String>("u")) {
+                    Ok(Some(Ok(u))) => Ok(Some(url_key(&u))),
+                    Ok(Some(Err(e))) | Err(e) => {
+                        errors.bad_record(source, line_num, line, e)?;
+                        Ok(None)
+                    }
+                    Ok(None) => {
+                        errors.bad_record(source, line_num, line, "Document has no 'u' field")?;
+                        Ok(None)
+                    }
+                }
+            })
+            .collect::<Result<Vec<_>, BadRecord>>()?;
+
+        for url in urls.into_iter().flatten() {
+            sorter.push(url)?;
+        }
+    }
+    errors.finish()?;
+
+    info!(
+        "Writing index of {} documents from {} sorted runs",
+        num_read,
+        sorter.num_runs()
+    );
+    let num_urls = write_set(&args.output, sorter.finish()?)?;
+    info!("{} unique urls", num_urls);
+
+    memory_usage();
+    info!("Elapsed time: {:.2} s", now.elapsed().as_secs_f32());
+    info!("Finished");
+    Ok(())
+}
@@ -1,9 +1,15 @@
 use clap::Parser;
 use env_logger::Env;
 use log::{debug, info};
+use std::path::Path;
+use std::thread;
 use std::time::Instant;
 
+use monotextor_utils::checkpoint::Checkpoint;
 use monotextor_utils::dedup::DedupFilter;
+use monotextor_utils::input::{InputManifest, InputSource};
+use monotextor_utils::onerror::{ErrorArgs, ErrorHandler};
+use monotextor_utils::split::{Output, ZSplit};
 use monotextor_utils::utils::memory_usage;
 
 #[derive(Parser)]
@@ -28,30 +34,188 @@ struct Args {
         help = "Add the size of the cluster to each document metadata"
     )]
     add_cluster_size: bool,
+    #[arg(
+        short,
+        long,
+        help = "Write the output to zstd compressed splits with this prefix, instead of stdout. \
+                Can also be a name template like 'batch_{N}.jsonl.zst'"
+    )]
+    output_prefix: Option<String>,
+    #[arg(
+        long,
+        default_value_t = 20,
+        requires = "output_prefix",
+        help = "Uncompressed size per each output split in GB"
+    )]
+    split_size: usize,
+    #[arg(
+        long,
+        requires = "output_prefix",
+        help = "Maximum number of documents per each output split"
+    )]
+    split_docs: Option<usize>,
+    #[arg(long, default_value_t = 10, requires = "output_prefix")]
+    compression_level: i32,
+    #[arg(long, default_value_t = 4, requires = "output_prefix")]
+    compression_threads: u32,
+    #[arg(
+        long,
+        requires = "output_prefix",
+        help = "Checkpoint file, progress is saved there each time an output split is completed. \
+                If it exists, processing is resumed from it."
+    )]
+    checkpoint: Option<String>,
+    #[arg(
+        long,
+        default_value_t = 4,
+        help = "Number of input files read and decompressed at the same time"
+    )]
+    reader_threads: usize,
+    #[arg(
+        long,
+        help = "Input manifest written by mhindex. Check that the input files are the same \
+                that were indexed. By default, the manifest next to the cluster file \
+                ('clusters.zst' -> 'clusters.manifest.json') is used if it exists"
+    )]
+    input_manifest: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "input_manifest",
+        help = "Count the input documents before deduplicating, if there is no input manifest. \
+                All the input is read twice and it can not be used with stdin. \
+                Otherwise, a different number of documents than in the cluster file \
+                is only detected at the end"
+    )]
+    count_input: bool,
+    #[command(flatten)]
+    errors: ErrorArgs,
 
     #[arg(help = "File containg the clusters array/s of duplicates.")]
     clusterfile: String,
-    #[arg(help = "zstd compressed jsonl files to be filtered.")]
+    #[arg(help = "jsonl files, globs or directories to be filtered, compressed or not.")]
     files: Vec<String>,
 }
 
-fn main() {
+const OUTPUT_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+// Number of lines per batch when counting the input documents
+const COUNT_BATCH_SIZE: usize = 100_000;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let now = Instant::now();
     let args = Args::parse();
+    let input = InputSource::new(&args.files)
+        .unwrap_or_else(|e| panic!("{}", e))
+        .with_readers(args.reader_threads);
+    let files = input.files();
+
+    // the manifest written by mhindex, otherwise look for it next to the cluster file
+    let input_manifest = args.input_manifest.clone().or_else(|| {
+        let path = manifest_path(&args.clusterfile);
+        Path::new(&path).is_file().then(|| {
+            info!("Using input manifest '{}'", path);
+            path
+        })
+    });
+
+    // count the documents while the cluster file is being read
+    let counter = match (&input_manifest, args.count_input) {
+        (None, true) => {
+            // stdin would be consumed before deduplicating
+            if files.iter().any(|f| f == "-") {
+                return Err("Input documents can not be counted when reading from stdin".into());
+            }
+            let input = input.clone();
+            Some(thread::spawn(move || {
+                InputManifest::count(&input, COUNT_BATCH_SIZE)
+            }))
+        }
+        _ => None,
+    };
+
+    let checkpoint = match &args.checkpoint {
+        Some(path) => Checkpoint::load_for(path, files).expect("Error loading checkpoint"),
+        None => None,
+    };
+    if let Some(checkpoint) = &checkpoint {
+        info!(
+            "Resuming from checkpoint: file {}, line {}, split {}",
+            checkpoint.file_idx, checkpoint.line_offset, checkpoint.shard
+        );
+    }
+    let checkpoint = checkpoint.unwrap_or_else(|| Checkpoint::new(files));
+
+    let output = match &args.output_prefix {
+        Some(prefix) => Output::Split(
+            ZSplit::starting_at(
+                prefix,
+                checkpoint.shard,
+                args.split_size * 1_000_000_000,
+                args.compression_level,
+                args.compression_threads,
+                OUTPUT_BUFFER_SIZE,
+            )
+            .expect("Error creating output file")
+            .with_max_docs(args.split_docs.unwrap_or(usize::MAX)),
+        ),
+        None => Output::stdout(OUTPUT_BUFFER_SIZE),
+    };
 
     info!("Reading clusterfile");
     let mut deduper = DedupFilter::new(
         args.clusterfile,
         args.print_duplicates,
         args.add_cluster_size,
+        output,
+    );
+    debug!(
+        "{} documents in clusterfile, {} to be kept",
+        deduper.num_docs,
+        deduper.clusters.num_kept()
     );
-    debug!("Parents array: {:?}", deduper.uf.parents);
 
+    // refuse to run if the input is not the same that was indexed
+    // otherwise the ids do not match, and it would be noticed after writing all the output
+    let num_input_docs = if let Some(path) = &input_manifest {
+        let indexed = InputManifest::load(path).expect("Error reading input manifest");
+        let current = InputManifest::new(&input, None).expect("Error reading input files");
+        if let Err(e) = indexed.check_files(&current) {
+            return Err(format!(
+                "Input is not the same that was indexed in '{}': {}",
+                path, e
+            )
+            .into());
+        }
+        indexed.num_docs
+    } else if let Some(counter) = counter {
+        info!("Counting input documents");
+        let counted = counter
+            .join()
+            .unwrap()
+            .expect("Error counting input documents");
+        counted.num_docs
+    } else {
+        None
+    };
+    if let Some(num_input_docs) = num_input_docs {
+        if num_input_docs != deduper.num_docs {
+            return Err(format!(
+                "Number of input docs is different than in cluster file: {} vs {}",
+                num_input_docs, deduper.num_docs
+            )
+            .into());
+        }
+    }
+
+    let errors = ErrorHandler::new(&args.errors).expect("Error creating quarantine file");
     info!("Reading documents and discarding duplicates");
-    for f in &args.files {
-        deduper.filter_dups(f);
+    if let Err(e) = deduper.filter_files(&input, &errors, checkpoint, args.checkpoint.as_deref()) {
+        // keep the completed splits and the checkpoint, so it can be resumed
+        deduper.abort()?;
+        errors.finish()?;
+        return Err(format!("Error filtering documents: {}", e).into());
     }
+    errors.finish().expect("Error writing quarantine file");
     let pct = (deduper.num_unique as f32 / deduper.num_docs as f32) * 100.0;
     info!(
         "Duplicates discarded, {} documents kept ({:.2} %)",
@@ -60,11 +224,23 @@ fn main() {
 
     memory_usage();
     info!("Elapsed time: {:.2} s", now.elapsed().as_secs_f32());
-    info!("Finished");
     if deduper.num_read_docs != deduper.num_docs {
         panic!(
             "Number of read docs is different than in cluster file: {} vs {}",
             deduper.num_read_docs, deduper.num_docs
         );
     }
+    // close the last split before removing the checkpoint
+    deduper.finish().expect("Error finishing output");
+    if let Some(path) = &args.checkpoint {
+        Checkpoint::remove(path).expect("Error removing checkpoint");
+    }
+    info!("Finished");
+    Ok(())
+}
+
+// Manifest next to a cluster file, named as 10.index writes it
+fn manifest_path(clusterfile: &str) -> String {
+    let name = clusterfile.strip_suffix(".zst").unwrap_or(clusterfile);
+    format!("{name}.manifest.json")
 }
@@ -1,20 +1,20 @@
-use std::fs::File;
+use std::fs;
 use std::hash::{Hash, Hasher};
-use std::io::{BufRead, BufReader};
-use std::sync::mpsc::sync_channel;
-use std::thread;
+use std::io::Write;
 use std::time::Instant;
 use std::mem::drop;
 
 use ahash::AHasher;
 use clap::Parser;
 use env_logger::Env;
-use fastbloom_rs::{FilterBuilder, Membership};
-use glob::glob;
-use log::{error, info, debug};
+use fastbloom_rs::{BloomFilter, FilterBuilder, Membership};
+use log::{info, debug};
+use memmap2::Mmap;
 use parse_size::parse_size;
-use zstd::stream::read::Decoder;
 
+use monotextor_utils::checkpoint::{bloom_path, remove_if_exists, write_atomic, Checkpoint};
+use monotextor_utils::input::InputSource;
+use monotextor_utils::onerror::{ErrorArgs, ErrorHandler};
 use monotextor_utils::split::ZSplit;
 use monotextor_utils::utils::memory_usage;
 use monotextor_utils::DocumentText;
@@ -22,9 +22,9 @@ use monotextor_utils::DocumentText;
 #[derive(Parser)]
 #[clap(version, about = "Exact deduplication")]
 struct Args {
-    #[clap(help = "Output file prefix")]
+    #[clap(help = "Output file prefix or name template (e.g. 'batch_{N}.jsonl.zst')")]
     out_prefix: String,
-    #[clap(help = "List or glob of zstd compressed jsonl files to be filtered.")]
+    #[clap(help = "List of jsonl files, globs or directories to be filtered, compressed or not.")]
     files: Vec<String>,
 
     #[clap(long, short, help="Estimated number of elements",
@@ -37,6 +37,8 @@ struct Args {
         help = "Uncompressed size per each output batch in GB"
     )]
     split_size: usize,
+    #[clap(long, help = "Maximum number of documents per each output batch")]
+    split_docs: Option<usize>,
     #[clap(long, short = 't')]
     num_threads: u32,
     #[clap(long, short, default_value_t = 10)]
@@ -44,98 +46,158 @@ struct Args {
     #[clap(long, short, help="Buffer size in bytes",
            value_parser = |s: &str| parse_size(s))]
     buffer_size: u64,
+    #[clap(
+        long,
+        help = "Checkpoint file, progress and BloomFilter state are saved there \
+                each time an output split is completed. If it exists, processing is resumed from it."
+    )]
+    checkpoint: Option<String>,
+    #[clap(flatten)]
+    errors: ErrorArgs,
 }
 
+// Number of lines sent at a time by the reading thread
+const BATCH_SIZE: usize = 10_000;
+
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut s = AHasher::default();
     t.hash(&mut s);
     s.finish()
 }
 
-fn main() {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-    let mut args = Args::parse();
+    let args = Args::parse();
     if args.files.len() < 1 {
-        error!("error: files requires at least 1 values");
-        std::process::exit(1);
+        return Err("files requires at least 1 values".into());
     }
 
-    // If only one element, do glob expansion
-    if args.files.len() == 1 {
-        info!("Expanding glob");
-        let mut files: Vec<String> = glob(&args.files[0])
-            .expect("Failed to expand glob")
-            .into_iter()
-            .map(|p| {
-                match p {
-                    Ok(path) => path.to_str().unwrap().to_string(),
-                    Err(e) => panic!("Could not read file {:?} from glob", e),
-                }
-            })
-            .collect();
-        args.files.clear();
-        args.files.append(&mut files);
-        debug!("Expanded glob to {:?}", args.files);
-    }
+    // each argument can be a file, a glob or a directory
+    let input = InputSource::new(&args.files).unwrap_or_else(|e| panic!("{}", e));
+    let files = input.files();
+    debug!("Expanded input to {:?}", files);
+
+    let checkpoint = match &args.checkpoint {
+        Some(path) => Checkpoint::load_for(path, files).expect("Error loading checkpoint"),
+        None => None,
+    };
 
-    info!("Initializing BloomFilter");
     let now = Instant::now();
-    // let mut index = BloomFilter::with_false_pos(0.001)
-    //     .seed(&42)
-    //     .expected_items(args.num_elements as usize);
-    let mut index = FilterBuilder::new(args.num_elements, 0.001).build_bloom_filter();
+    let mut index = match (&args.checkpoint, &checkpoint) {
+        (Some(path), Some(checkpoint)) => {
+            info!(
+                "Resuming from checkpoint: file {}, line {}, split {}",
+                checkpoint.file_idx, checkpoint.line_offset, checkpoint.shard
+            );
+            let bloom_file = bloom_path(path, checkpoint.shard);
+            let hashes = checkpoint
+                .bloom_hashes
+                .expect("Checkpoint does not have BloomFilter state");
+            // the state is mapped instead of read into memory,
+            // so there is only one copy of the filter in memory, the one being built
+            let bytes = fs::File::open(&bloom_file)
+                .and_then(|file| unsafe { Mmap::map(&file) })
+                .unwrap_or_else(|e| panic!("Error reading BloomFilter file '{bloom_file}': {e}"));
+            BloomFilter::from_u8_array(&bytes, hashes)
+        }
+        _ => {
+            info!("Initializing BloomFilter");
+            // let mut index = BloomFilter::with_false_pos(0.001)
+            //     .seed(&42)
+            //     .expected_items(args.num_elements as usize);
+            FilterBuilder::new(args.num_elements, 0.001).build_bloom_filter()
+        }
+    };
     info!(
         "BloomFilter initialization took {:.2} s",
         now.elapsed().as_secs_f32()
     );
+    let mut checkpoint = checkpoint.unwrap_or_else(|| Checkpoint::new(files));
 
     info!("Processing");
     let now = Instant::now();
-    let (sender, receiver) = sync_channel(100000);
-    let mut writer = ZSplit::new(
+    let mut writer = ZSplit::starting_at(
         &args.out_prefix,
+        checkpoint.shard,
         args.split_size * 1_000_000_000,
         args.compression_level,
         args.num_threads,
         args.buffer_size as usize,
     )
-    .unwrap();
-
-    let mut num_docs = 0;
-    let mut kept_docs = 0;
-    thread::spawn(move || {
-        for filename in args.files {
-            let file =
-                File::open(&filename).expect(format!("Error opening file '{filename}'").as_str());
-            let decoder = Decoder::new(file)
-                .expect(format!("Uncompressed or corrupted file '{filename}'").as_str());
-            let reader = BufReader::with_capacity(args.buffer_size as usize, decoder);
+    .unwrap()
+    .with_max_docs(args.split_docs.unwrap_or(usize::MAX));
 
-            for line_res in reader.split(b'\n') {
-                let line = line_res.unwrap();
-                sender.send(line).unwrap();
+    let errors = ErrorHandler::new(&args.errors).expect("Error creating quarantine file");
+    let mut num_docs = checkpoint.num_read_docs;
+    let mut kept_docs = checkpoint.num_kept_docs;
+    let (start_idx, skip_lines) = (checkpoint.file_idx, checkpoint.line_offset);
+    // reading and decompression is done in a background thread
+    for batch in input.batches_from(BATCH_SIZE, start_idx, skip_lines) {
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                // keep the completed splits and the checkpoint, so it can be resumed
+                writer.abort()?;
+                errors.finish()?;
+                return Err(e.into());
             }
-        }
-    });
-
-    while let Ok(line) = receiver.recv() {
-        let doc: DocumentText = serde_json::from_slice(&line).expect("Error parsing JSON document");
-        num_docs += 1;
+        };
+        let file_idx = batch.file_idx;
+        for (i, line) in batch.lines.into_iter().enumerate() {
+            // keep the position, so bad records can be located
+            let line_num = batch.first_line + i;
+            num_docs += 1;
+            let doc: DocumentText = match serde_json::from_slice(&line) {
+                Ok(doc) => doc,
+                Err(e) => {
+                    if let Err(e) = errors.bad_record(input.name(file_idx), line_num, &line, e) {
+                        // keep the completed splits and the checkpoint, so it can be resumed
+                        writer.abort()?;
+                        errors.finish()?;
+                        return Err(e.into());
+                    }
+                    continue;
+                }
+            };
 
-        // let hash = calculate_hash(&doc.text);
-        // if !index.insert_hash(hash) {
-        //     kept_docs += 1;
-        //     println!("{}", line);
-        // }
-        let bytes = &doc.text.as_bytes();
-        if !index.contains(bytes) {
-            kept_docs += 1;
-            index.add(bytes);
-            writer.write_line(&line).unwrap();
-            // writer.write(b"\n").unwrap();
+            // let hash = calculate_hash(&doc.text);
+            // if !index.insert_hash(hash) {
+            //     kept_docs += 1;
+            //     println!("{}", line);
+            // }
+            let bytes = &doc.text.as_bytes();
+            if !index.contains(bytes) {
+                // split completed, save the state previous to this document
+                if let (Some(path), true) = (&args.checkpoint, writer.is_full()) {
+                    writer.rotate().unwrap();
+                    let previous_shard = checkpoint.shard;
+                    checkpoint.file_idx = file_idx;
+                    checkpoint.line_offset = line_num - 1;
+                    checkpoint.shard = writer.num_splits();
+                    checkpoint.num_read_docs = num_docs - 1;
+                    checkpoint.num_kept_docs = kept_docs;
+                    checkpoint.bloom_hashes = Some(index.hashes());
+                    write_atomic(&bloom_path(path, checkpoint.shard), |bloom_writer| {
+                        bloom_writer.write_all(index.get_u8_array())
+                    })
+                    .expect("Error writing BloomFilter state");
+                    checkpoint.save(path).expect("Error saving checkpoint");
+                    remove_if_exists(&bloom_path(path, previous_shard)).unwrap();
+                    debug!("Saved checkpoint {:?}", checkpoint);
+                }
+                kept_docs += 1;
+                index.add(bytes);
+                writer.write_line(&line).unwrap();
+                // writer.write(b"\n").unwrap();
+            }
         }
     }
-    writer.flush().unwrap();
+    // close the last split before removing the checkpoint
+    writer.finish().unwrap();
+    if let Some(path) = &args.checkpoint {
+        Checkpoint::remove(path).unwrap();
+    }
+    errors.finish().unwrap();
 
     memory_usage();
     drop(index);
@@ -152,4 +214,5 @@ fn main() {
         num_docs as f32 / now.elapsed().as_secs_f32()
     );
     // info!("Unique elements: {}", index.len());
+    Ok(())
 }
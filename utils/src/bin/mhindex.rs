@@ -2,11 +2,12 @@ use clap::Parser;
 use env_logger::Env;
 use gaoya::minhash::calculate_minhash_params;
 use log::info;
-use serde_json::Result;
 use std::time::Instant;
 
 use monotextor_utils::indexer::Indexer;
+use monotextor_utils::input::{InputManifest, InputSource};
 use monotextor_utils::minhash_processor::Tokenization;
+use monotextor_utils::onerror::{ErrorArgs, ErrorHandler};
 use monotextor_utils::utils::memory_usage;
 
 #[derive(Parser)]
@@ -78,12 +79,26 @@ struct Args {
         help = "Print MinHash parameters and finish."
     )]
     dry_run: bool,
+    #[arg(
+        long,
+        default_value_t = 4,
+        help = "Number of input files read and decompressed at the same time"
+    )]
+    reader_threads: usize,
+    #[arg(
+        long,
+        help = "Write the input files, their sizes and number of documents to this JSON file, \
+                so dedup can check that it is run with the same input"
+    )]
+    manifest: Option<String>,
+    #[command(flatten)]
+    errors: ErrorArgs,
 
-    #[arg(help = "zstd compressed jsonl files to be indexed.")]
+    #[arg(help = "jsonl files, globs or directories to be indexed, compressed or not.")]
     files: Vec<String>,
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let args = Args::parse();
     let now = Instant::now();
@@ -116,11 +131,19 @@ fn main() -> Result<()> {
 
     info!("Indexing documents");
     // Read, deserialize, hash and index each file
+    let errors = ErrorHandler::new(&args.errors).expect("Error creating quarantine file");
+    let input = InputSource::new(&args.files)
+        .unwrap_or_else(|e| panic!("{}", e))
+        .with_readers(args.reader_threads);
     let mut global_id = 0; // document id
-    for file in &args.files {
-        indexer.index_file(file, &mut global_id);
-    }
+    let counts = indexer.index_input(&input, &mut global_id, &errors)?;
+    errors.finish().expect("Error writing quarantine file");
     info!("Indexed {} documents", global_id);
+    if let Some(path) = &args.manifest {
+        InputManifest::new(&input, Some(&counts))
+            .and_then(|manifest| manifest.save(path))
+            .expect("Error writing input manifest");
+    }
 
     info!("Finding clusters");
     let uf = indexer.find_clusters();
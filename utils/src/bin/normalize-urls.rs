@@ -0,0 +1,72 @@
+/* Print the normalized url of each document, one per line
+ * These are the keys of the url indexes that robots-filter searches with the robots.txt rules,
+ * so urls and robots.txt patterns are compared with the same normalization.
+ */
+use std::io::{self, BufWriter, Write};
+
+use clap::Parser;
+use env_logger::Env;
+use rayon::prelude::*;
+
+use monotextor_utils::input::InputSource;
+use monotextor_utils::onerror::{BadRecord, ErrorArgs, ErrorHandler};
+use monotextor_utils::splice::RawDocument;
+use monotextor_utils::url::url_key;
+
+#[derive(Parser)]
+#[command(version, about = "Print the normalized url of each JSONL document")]
+struct Args {
+    #[arg(
+        help = "JSONL files with 'u' field, globs or directories, compressed or not. \
+                If not provided, read from stdin"
+    )]
+    files: Vec<String>,
+    #[command(flatten)]
+    errors: ErrorArgs,
+}
+
+const BATCH_SIZE: usize = 100_000;
+
+fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+    let input = InputSource::new(&args.files)?;
+    let errors = ErrorHandler::new(&args.errors)?;
+    let mut output = BufWriter::new(io::stdout().lock());
+
+    for batch in input.batches(BATCH_SIZE) {
+        let batch = batch?;
+        let source = input.name(batch.file_idx);
+        let urls = batch
+            .lines
+            .par_iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let line_num = batch.first_line + i;
+                let Some(line_str) = errors.decode(source, line_num, line)? else {
+                    return Ok(None);
+                };
+                let u = match RawDocument::parse(line_str).map(|doc| doc.get::<String>("u")) {
+                    Ok(Some(Ok(u))) => u,
+                    Ok(Some(Err(e))) | Err(e) => {
+                        errors.bad_record(source, line_num, line, e)?;
+                        return Ok(None);
+                    }
+                    Ok(None) => {
+                        errors.bad_record(source, line_num, line, "Document has no 'u' field")?;
+                        return Ok(None);
+                    }
+                };
+                Ok(Some(url_key(&u)))
+            })
+            .collect::<Result<Vec<_>, BadRecord>>()?;
+
+        for url in urls.into_iter().flatten() {
+            output.write_all(url.as_bytes())?;
+            output.write_all(b"\n")?;
+        }
+    }
+    output.flush()?;
+    errors.finish()?;
+    Ok(())
+}
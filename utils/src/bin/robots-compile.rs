@@ -0,0 +1,89 @@
+/* Compile the robots.txt of a collection into an index of rules by origin (scheme, host and port)
+ * The robots.txt are read in the JSONL format of warc2text --robots-process
+ * ('u' field with the url of the robots.txt and 'p' with its body).
+ * The index can be used to evaluate each document url directly (annotator --robots-index)
+ * instead of searching all the urls of the collection with each rule (robots-filter).
+ */
+use std::time::Instant;
+
+use clap::Parser;
+use env_logger::Env;
+use log::info;
+
+use monotextor_utils::input::InputSource;
+use monotextor_utils::onerror::{ErrorArgs, ErrorHandler};
+use monotextor_utils::robots::{parse_timestamp, RobotsDoc, RobotsIndexBuilder, RobotsParser};
+use monotextor_utils::url::Url;
+use monotextor_utils::utils::memory_usage;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Compile robots.txt files into an index of rules by origin"
+)]
+struct Args {
+    #[arg(help = "Output index path, rules are written to '<path>.rules'")]
+    output: String,
+    #[arg(help = "robots.txt JSONL files, globs or directories, compressed or not")]
+    files: Vec<String>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "ia_archiver,ccbot,*",
+        help = "Comma separated list of user-agents whose rules are followed"
+    )]
+    user_agents: Vec<String>,
+    #[command(flatten)]
+    errors: ErrorArgs,
+}
+
+const BATCH_SIZE: usize = 10_000;
+
+fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let now = Instant::now();
+    let args = Args::parse();
+
+    let input = InputSource::new(&args.files)?;
+    let errors = ErrorHandler::new(&args.errors)?;
+    let parser = RobotsParser::new(&args.user_agents);
+    let mut builder = RobotsIndexBuilder::new();
+    let mut num_robots = 0;
+
+    info!("Parsing robots.txt");
+    for batch in input.batches(BATCH_SIZE) {
+        let batch = batch?;
+        let source = input.name(batch.file_idx);
+        for (i, line) in batch.lines.iter().enumerate() {
+            let doc: RobotsDoc = match serde_json::from_slice(line) {
+                Ok(doc) => doc,
+                Err(e) => {
+                    errors.bad_record(source, batch.first_line + i, line, e)?;
+                    continue;
+                }
+            };
+            num_robots += 1;
+            let mut rule_sets = parser.parse(&doc.p);
+            // rules of agents that do not disallow anything are not needed,
+            // but the origin is stored so other robots.txt are not used as fallback
+            rule_sets.retain(|rules| rules.iter().any(|rule| !rule.allow));
+            // each robots.txt is kept with the time it was fetched,
+            // so documents can be evaluated with the one in effect when they were crawled
+            let fetched = doc.ts.as_deref().and_then(parse_timestamp);
+            builder.add(&Url::parse(&doc.u).origin(), fetched, rule_sets);
+        }
+    }
+    errors.finish()?;
+
+    info!(
+        "Writing rules of {} origins from {} robots.txt",
+        builder.num_origins(),
+        num_robots
+    );
+    builder.write(&args.output)?;
+
+    memory_usage();
+    info!("Elapsed time: {:.2} s", now.elapsed().as_secs_f32());
+    info!("Finished");
+    Ok(())
+}
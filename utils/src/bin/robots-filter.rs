@@ -1,42 +1,142 @@
 /* Small program that uses an FST index of URLs to query from robots.txt patterns
- * The robots.txt patterns are expected in tab separated format, where each line
+ * The robots.txt can be given in the JSONL format of warc2text --robots-process
+ * ('u' field with the url of the robots.txt and 'p' with its body)
+ * or as already extracted patterns in tab separated format, where each line
  * is a (Dis)allow entry in a robots.txt file. The first field must be the pattern
- * (domain + path) and the second field must be a 0 or 1, whether if it's disallowed or not.
+ * (origin + path) and the second field must be a 0 or 1, whether if it's disallowed or not.
  * All the entries of a robots.txt must appear contiguous.
  *
- * This assumes the user has taken care of a previous parsing of the robots.txt
+ * Rules apply to the urls of the origin (scheme, host and port) of their robots.txt.
+ * Depending on --origin-fallback, they also apply to the http/https and www/bare host
+ * variants of the origin that do not have a robots.txt of their own.
+ *
+ * With robots.txt files, only the rules for the requested user-agents are used.
+ * With patterns, this assumes the user has taken care of a previous parsing of the robots.txt
  * dumping the entries that considers relevant (entries that match a certain set of
  * user-agents, for example) to the input file of this program.
  */
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, BufWriter, Write};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, RwLock};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use env_logger::Env;
+use fst::automaton::AlwaysMatch;
+use fst::set::Stream;
 use fst::{IntoStreamer, Set, Streamer};
 use indicatif::{ParallelProgressIterator, ProgressBar, ProgressFinish, ProgressStyle};
-use log::{debug, warn};
-use patricia_tree::StringPatriciaSet;
+use log::{debug, info, warn};
+use patricia_tree::StringPatriciaMap;
 use rayon::prelude::*;
-use regex::Regex;
-use regex_automata::dense;
+
+use monotextor_utils::extsort::{write_set, SortArgs};
+use monotextor_utils::input::{self, InputSource};
+use monotextor_utils::onerror::{BadRecord, ErrorArgs, ErrorHandler};
+use monotextor_utils::robots::{
+    is_allowed, pattern_matches, CompiledPattern, OriginFallback, PatternAutomaton, RobotsDoc,
+    RobotsParser,
+};
+use monotextor_utils::url::{disallowed_key, split_key, url_key, Url};
 
 #[derive(Parser)]
 #[command(version, about = "Generate a list of disallowed URLs by robots.txt")]
 struct Args {
     #[arg(help = "FST indexed URLs to search in")]
     indexpath: String,
-    #[arg(help = "List of allowance URL patterns from robots.txt")]
+    #[arg(help = "List of robots.txt JSONL or allowance URL patterns files, globs or directories")]
     allowancefiles: Vec<String>,
+    #[arg(value_enum, long, default_value_t = InputFormat::Tsv, help = "Format of the input files")]
+    format: InputFormat,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "ia_archiver,ccbot,*",
+        help = "Comma separated list of user-agents whose rules are followed, \
+                when reading robots.txt files"
+    )]
+    user_agents: Vec<String>,
+    #[arg(value_enum, long, default_value_t = OriginFallback::Any,
+          help = "Variants of an origin without robots.txt that the rules of the origin apply to")]
+    origin_fallback: OriginFallback,
+    #[arg(
+        long,
+        default_value_t = 500_000,
+        help = "Maximum number of disallowed urls of an origin kept in memory to be checked \
+                against allow rules. Beyond that, each url is evaluated with all the rules, which is slower"
+    )]
+    max_disallowed: usize,
+    #[arg(
+        long,
+        default_value_t = 5_000_000,
+        help = "Ban origins with a rule that matches more than this number of urls, \
+                the rest of their rules are not searched"
+    )]
+    max_results: usize,
+    #[arg(
+        long,
+        help = "Disallow all the urls of banned origins. By default, only the urls disallowed \
+                before the ban are, so the rest of the urls of the origin are allowed"
+    )]
+    disallow_banned: bool,
+    #[arg(
+        long,
+        help = "Write the banned origins to this file, with the rule and number of urls that \
+                caused the ban, tab separated"
+    )]
+    banned_report: Option<String>,
+    #[arg(
+        long,
+        default_value_t = 100_000,
+        help = "Maximum number of compiled wildcard patterns cached to be reused across origins"
+    )]
+    pattern_cache_size: usize,
+    #[arg(
+        long,
+        help = "Write the disallowed urls sorted to this FST set, instead of printing them"
+    )]
+    output_fst: Option<String>,
+    #[command(flatten)]
+    sort: SortArgs,
+    #[command(flatten)]
+    errors: ErrorArgs,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum InputFormat {
+    // origin+pattern and 0/1 allowed, tab separated
+    Tsv,
+    // warc2text robots.txt JSONL
+    Robots,
+}
+
+// A pattern (origin + path), whether it is allowed or not and its group of rules
+// rules of different groups (the user-agents of a robots.txt) are evaluated separately
+type Entry = Result<(String, bool, usize), Box<dyn std::error::Error>>;
+
+// Rule and number of matched urls that caused the ban of an origin
+struct Ban {
+    rule: String,
+    num_results: usize,
 }
 
 struct SharedState {
     index: Arc<Set<Vec<u8>>>,
     sender: Sender<String>,
-    banned: Arc<RwLock<HashSet<String>>>,
+    banned: Arc<RwLock<HashMap<String, Ban>>>,
+    // parser for robots.txt input, None for patterns input
+    parser: Option<Arc<RobotsParser>>,
+    fallback: OriginFallback,
+    // origins that have a robots.txt, fallback is not applied to them
+    origins: Arc<HashSet<String>>,
+    max_disallowed: usize,
+    max_results: usize,
+    disallow_banned: bool,
+    // compiled wildcard patterns by path, the same ones appear in the robots.txt of many origins
+    patterns: Arc<RwLock<HashMap<String, Arc<CompiledPattern>>>>,
+    pattern_cache_size: usize,
+    errors: Arc<ErrorHandler>,
 }
 
 impl Clone for SharedState {
@@ -45,191 +145,553 @@ impl Clone for SharedState {
             index: self.index.clone(),
             sender: self.sender.clone(), // Clone the sender for thread safety
             banned: self.banned.clone(),
+            parser: self.parser.clone(),
+            fallback: self.fallback,
+            origins: self.origins.clone(),
+            max_disallowed: self.max_disallowed,
+            max_results: self.max_results,
+            disallow_banned: self.disallow_banned,
+            patterns: self.patterns.clone(),
+            pattern_cache_size: self.pattern_cache_size,
+            errors: self.errors.clone(),
         }
     }
 }
 
-// escape metasequences characters like regex_syntax::escape
-// except *, which is converted to .*
-// and anchors are removed
-pub fn is_meta_character(c: char) -> bool {
-    match c {
-        '\\' | '.' | '+' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}' | '#' | '&' | '-'
-        | '~' => true,
-        _ => false,
+// Urls of the index that match a rule
+// patterns without wildcards are a range of the index, the rest are searched with an automaton
+enum Query<'a> {
+    Range(Stream<'a, AlwaysMatch>),
+    Pattern(Stream<'a, PatternAutomaton<'a>>),
+}
+
+impl<'a> Query<'a> {
+    fn new(index: &'a Set<Vec<u8>>, origin: &'a str, pattern: &'a CompiledPattern) -> Self {
+        if !pattern.is_literal() {
+            return Query::Pattern(
+                index
+                    .search(PatternAutomaton::new(origin, pattern))
+                    .into_stream(),
+            );
+        }
+        let prefix = [origin.as_bytes(), pattern.prefix()].concat();
+        let range = index.range().ge(&prefix);
+        let range = match (pattern.is_anchored(), prefix_end(&prefix)) {
+            (true, _) => range.le(&prefix),
+            (false, Some(end)) => range.lt(end),
+            (false, None) => range,
+        };
+        Query::Range(range.into_stream())
+    }
+
+    fn next(&mut self) -> Option<&[u8]> {
+        match self {
+            Query::Range(stream) => stream.next(),
+            Query::Pattern(stream) => stream.next(),
+        }
     }
 }
-pub fn escape(text: &str) -> String {
-    let mut buf = String::with_capacity(text.len());
-    for c in text.chars() {
-        if c == '^' || c == '$' {
-            continue;
-        } else if c == '*' {
-            buf.push('.');
-        } else if is_meta_character(c) {
-            buf.push('\\');
+
+// First key after all the keys that start with a prefix, None if there is no such key
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
         }
-        buf.push(c);
     }
-    buf
+    None
+}
+
+// Compiled pattern of a rule path, reused from the cache if possible
+// literal patterns are not cached, they do not need to be compiled
+fn compiled_pattern(state: &SharedState, path: &str) -> Arc<CompiledPattern> {
+    if !path.contains('*') {
+        return Arc::new(CompiledPattern::new(path));
+    }
+    if let Some(pattern) = state.patterns.read().unwrap().get(path) {
+        return pattern.clone();
+    }
+    let pattern = Arc::new(CompiledPattern::new(path));
+    let mut patterns = state.patterns.write().unwrap();
+    // keep the cache bounded, starting again when it is full
+    if patterns.len() >= state.pattern_cache_size {
+        patterns.clear();
+    }
+    patterns.insert(path.to_string(), pattern.clone());
+    pattern
+}
+
+// A rule of the robots.txt of an origin
+struct DomainRule {
+    // path pattern
+    path: String,
+    // length of the path pattern, the longest matching rule is applied
+    priority: usize,
+    allowed: bool,
+    group: usize,
+}
+
+// Evaluate the path of a url with the rules of a group
+fn group_allows(rules: &[DomainRule], path: &str) -> bool {
+    is_allowed(
+        rules
+            .iter()
+            .map(|rule| (rule.allowed, rule.path.as_bytes())),
+        path,
+    )
+}
+
+// Open iterator over the (pattern, allowed, group) entries of a file
+fn file_entries(
+    state: &SharedState,
+    filepath: &str,
+) -> io::Result<Box<dyn Iterator<Item = Entry>>> {
+    let reader = input::open(filepath)?;
+    let lines = Lines {
+        reader,
+        errors: state.errors.clone(),
+        source: filepath.to_string(),
+        line_num: 0,
+    };
+    Ok(match &state.parser {
+        Some(parser) => Box::new(robots_entries(lines, Arc::clone(parser))),
+        None => Box::new(tsv_entries(lines)),
+    })
+}
+
+// Origins that have a robots.txt in a file
+// robots.txt that do not disallow anything count too, they are not in the entries
+fn file_origins(
+    state: &SharedState,
+    filepath: &str,
+) -> Result<HashSet<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut origins = HashSet::new();
+    if state.parser.is_some() {
+        // bad records are skipped here, they are handled when the rules are read
+        for line in input::open(filepath)?.split(b'\n') {
+            if let Ok(doc) = serde_json::from_slice::<RobotsDoc>(&line?) {
+                origins.insert(Url::parse(&doc.u).origin());
+            }
+        }
+    } else {
+        for entry in file_entries(state, filepath)? {
+            let (url, _, _) = entry.map_err(|e| e.to_string())?;
+            origins.insert(split_key(&url).0.to_string());
+        }
+    }
+    Ok(origins)
 }
 
 fn process_file(
     state: &mut SharedState,
     filepath: &str,
 ) -> Result<(), Box<dyn std::error::Error + 'static>> {
-    // check if $ anchor at the end
-    let anchor_end = Regex::new(r"[\$\*]$")?;
-    // extract domain from url
-    let domain_re = Regex::new(r"^(https?://)?(www\.)?([^/]+)?.*$")?;
-
-    // Open iterator over the lines of the dissallowed list
-    let file = fs::File::open(filepath)?;
-    let lines = io::BufReader::new(file).lines();
+    let entries = file_entries(state, filepath)?;
 
     // asumes URLs coming grouped by each robots file
-    // so each group should be the same domain
-    // wee keep the domain name and the disallowed urls for that domain
-    let mut cur_domain = String::with_capacity(500);
-    let mut cur_allowance: StringPatriciaSet = StringPatriciaSet::new();
-    let mut warned_size = false;
+    // so each group should be the same origin
+    // wee keep the origin and the rules for that origin
+    let mut cur_origin = String::with_capacity(500);
+    let mut cur_rules: Vec<DomainRule> = Vec::new();
 
-    for (i, line_result) in lines.enumerate() {
-        // parse tab serparted lines
-        let line = line_result?;
-        let parts: Vec<_> = line.split('\t').collect();
-        let url = parts[0];
-        let allowed = match parts[1] {
-            "1" => true,
-            "0" => false,
-            _ => panic!(
-                "{}",
-                format!("Could not parse bool in line {}", i + 1).as_str()
-            ),
-        };
+    for entry in entries {
+        let (url, allowed, group) = entry?;
 
-        // Extract domain, check if has changed
+        // Extract origin, check if has changed
         // that means new robots.txt file
-        let domain = domain_re
-            .captures(&url)
-            .expect(format!("Failed parsing domain from url: {line}").as_str())
-            .get(3)
-            .ok_or("Could not obtain captured group 3")?
-            .as_str();
-        if cur_domain != domain {
-            // New domain, print the final list of disallowed
-            cur_domain.clear();
-            cur_domain.push_str(domain);
-            debug!("New domain! {cur_domain}");
-            for u in cur_allowance.iter() {
-                state.sender.send(format!("{}", u))?;
-            }
-            cur_allowance = StringPatriciaSet::new();
-            warned_size = false;
+        // urls are normalized, so the origin is everything before the path
+        let (origin, path) = split_key(&url);
+        if cur_origin != origin {
+            // New origin, print the final list of disallowed
+            process_origin(state, &cur_origin, &mut cur_rules, filepath)?;
+            cur_origin.clear();
+            cur_origin.push_str(origin);
+            debug!("New origin! {cur_origin}");
+            cur_rules.clear();
         }
-        if state.banned.read().unwrap().contains(&cur_domain) {
+        cur_rules.push(DomainRule {
+            path: path.to_string(),
+            priority: path.len(),
+            allowed,
+            group,
+        });
+    }
+    // print the disallowed of the last origin in the file
+    process_origin(state, &cur_origin, &mut cur_rules, filepath)?;
+
+    Ok(())
+}
+
+// Apply the rules of an origin to its urls
+// and to the urls of the variants of the origin that fall back to it
+fn process_origin(
+    state: &SharedState,
+    origin: &str,
+    rules: &mut [DomainRule],
+    filepath: &str,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    if rules.is_empty() {
+        return Ok(());
+    }
+    process_domain(state, origin, rules, filepath)?;
+
+    let candidates = state.fallback.candidates(&Url::parse(origin));
+    for variant in candidates.iter().skip(1) {
+        if state.origins.contains(variant) {
             continue;
         }
+        // the variant may have a preferred fallback with robots.txt too
+        let fallback = state
+            .fallback
+            .candidates(&Url::parse(variant))
+            .into_iter()
+            .find(|candidate| state.origins.contains(candidate));
+        if fallback.as_deref() == Some(origin) {
+            process_domain(state, variant, rules, filepath)?;
+        }
+    }
+    Ok(())
+}
+
+// Search the urls of an origin that match the rules and send the disallowed ones
+// The rules of each group are evaluated separately, a url is disallowed if any group disallows it
+fn process_domain(
+    state: &SharedState,
+    origin: &str,
+    rules: &mut [DomainRule],
+    filepath: &str,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    rules.sort_by_key(|rule| (rule.group, rule.allowed));
+    let groups: Vec<&[DomainRule]> = rules.chunk_by(|a, b| a.group == b.group).collect();
+    for (i, rules) in groups.iter().enumerate() {
+        // urls disallowed by a previous group have already been sent
+        let sent = |path: &str| groups[..i].iter().any(|rules| !group_allows(rules, path));
+        if state.banned.read().unwrap().contains_key(origin) {
+            return Ok(());
+        }
+        process_group(state, origin, rules, sent, filepath)?;
+    }
+    Ok(())
+}
+
+// Search the urls of an origin that match the rules of a group and send the disallowed ones
+// As in RFC 9309, the most specific rule (longest path pattern) that matches a url is applied,
+// and if an allow and a disallow rule are equally specific, allow wins.
+// So disallow rules are searched first, keeping the length of the longest match for each url,
+// then a url is removed if an allow rule at least as long matches it.
+// Rules must be sorted with disallow rules first.
+fn process_group(
+    state: &SharedState,
+    origin: &str,
+    rules: &[DomainRule],
+    sent: impl Fn(&str) -> bool,
+    filepath: &str,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let num_disallow = rules.iter().filter(|rule| !rule.allowed).count();
+    let (disallow_rules, allow_rules) = rules.split_at(num_disallow);
+
+    let mut disallowed: StringPatriciaMap<usize> = StringPatriciaMap::new();
+    let mut warned_size = false;
+    for (i, rule) in disallow_rules.iter().enumerate() {
+        let url = format!("{origin}{}", rule.path);
+        debug!("Searching rule {url}");
 
-        // Escape regex characters that are not supported
-        let escaped = escape(&url);
-
-        // If the url had $ at the end, do not add the .* suffix
-        let query;
-        if !anchor_end.is_match(&url) {
-            query = format!("{escaped}.*");
-        } else {
-            query = escaped.to_string();
-        }
-        debug!("Escaped url: {query}");
-
-        // Build searcher
-        let dense_dfa = dense::Builder::new()
-            .anchored(true)
-            .minimize(false)
-            .byte_classes(true)
-            .premultiply(true)
-            .reverse(false)
-            .build(&query)
-            .expect(format!("Escaped '{}' url '{}'", query, url).as_str());
-        let dfa = match dense_dfa {
-            dense::DenseDFA::PremultipliedByteClass(dfa) => dfa,
-            _ => unreachable!(),
-        };
         // search over the FST
-        let mut results = state.index.search(dfa).into_stream();
+        let pattern = compiled_pattern(state, &rule.path);
+        let mut results = Query::new(&state.index, origin, &pattern);
         let mut num_results = 0;
-        // iterate over the results
-        // for those allowed, remove the url from the set in case was added by a previous query
-        // for those disallowed, add them to the set for the current domain
+        // add the disallowed urls to the map with the most specific match
         while let Some(key) = results.next() {
             num_results += 1;
-            if allowed {
-                cur_allowance.remove(&String::from_utf8(key.to_vec())?);
-            } else {
-                // if the allowance list becomes too large, stop adding urls to it
-                if cur_allowance.len() > 500_000 {
-                    state
-                        .sender
-                        .send(format!("{}", String::from_utf8(key.to_vec())?))?;
-                    if !warned_size {
-                        warn!(
-                            "The in-memory list reached size limit for query '{}' in file '{}'",
-                            escaped, filepath
-                        );
-                        warned_size = true;
-                    }
-                } else {
-                    cur_allowance.insert(String::from_utf8(key.to_vec())?);
+            let key = std::str::from_utf8(key)?;
+            if let Some(priority) = disallowed.get_mut(key) {
+                *priority = rule.priority.max(*priority);
+            } else if disallowed.len() > state.max_disallowed {
+                // if the disallowed list becomes too large, stop adding urls to it
+                // and evaluate them with all the rules of the group instead,
+                // once, when they are found by the first disallow rule that matches them
+                let path = &key[origin.len()..];
+                let first_match = !disallow_rules[..i]
+                    .iter()
+                    .any(|rule| pattern_matches(rule.path.as_bytes(), path.as_bytes()));
+                if first_match && !group_allows(rules, path) && !sent(path) {
+                    state.sender.send(key.to_string())?;
+                }
+                if !warned_size {
+                    warn!(
+                        "The in-memory list reached size limit for query '{}' in file '{}'",
+                        url, filepath
+                    );
+                    warned_size = true;
                 }
+            } else {
+                disallowed.insert(key, rule.priority);
+            }
+            // stop searching, the origin is banned
+            if num_results > state.max_results {
+                break;
             }
         }
-        if num_results > 5_000_000 {
-            warn!("Very big query results '{}' with '{}'", num_results, url);
-            state.banned.write().unwrap().insert(cur_domain.clone());
+        if num_results > state.max_results {
+            warn!(
+                "Very big query results '{}' with '{}', banning origin",
+                num_results, url
+            );
+            let ban = Ban {
+                rule: rule.path.clone(),
+                num_results,
+            };
+            state
+                .banned
+                .write()
+                .unwrap()
+                .insert(origin.to_string(), ban);
+            if state.disallow_banned {
+                return disallow_origin(state, origin);
+            }
+            break;
         }
     }
 
+    // allow rules can only remove urls from the disallowed ones, so they are not searched
+    // in the index, remove the urls matched by an allow at least as specific as the disallow
+    for rule in allow_rules {
+        if disallowed.is_empty() {
+            break;
+        }
+        let allowed: Vec<String> = disallowed
+            .iter()
+            .filter(|(key, priority)| {
+                **priority <= rule.priority
+                    && pattern_matches(rule.path.as_bytes(), &key.as_bytes()[origin.len()..])
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in allowed {
+            disallowed.remove(key);
+        }
+    }
+
+    for (u, _) in disallowed.iter() {
+        if !sent(&u[origin.len()..]) {
+            state.sender.send(u)?;
+        }
+    }
+    Ok(())
+}
+
+// Send all the urls of an origin as disallowed
+fn disallow_origin(
+    state: &SharedState,
+    origin: &str,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let prefix = format!("{origin}/");
+    let mut results = state.index.range().ge(&prefix).into_stream();
+    while let Some(key) = results.next() {
+        if !key.starts_with(prefix.as_bytes()) {
+            break;
+        }
+        state.sender.send(std::str::from_utf8(key)?.to_string())?;
+    }
     Ok(())
 }
 
+// Lines of an input file, decoded according to the bad record policy
+// lines that are not valid UTF-8 are skipped, I/O errors and failed records are returned
+struct Lines {
+    reader: Box<dyn BufRead + Send>,
+    errors: Arc<ErrorHandler>,
+    source: String,
+    line_num: usize,
+}
+
+impl Lines {
+    fn bad_record(&self, line: &str, err: impl std::fmt::Display) -> Result<(), BadRecord> {
+        self.errors
+            .bad_record(&self.source, self.line_num, line.as_bytes(), err)
+    }
+}
+
+impl Iterator for Lines {
+    type Item = Result<String, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut line = Vec::new();
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(0) => return None,
+                Ok(_) => (),
+                Err(e) => return Some(Err(e.into())),
+            }
+            self.line_num += 1;
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            match self.errors.decode(&self.source, self.line_num, &line) {
+                Ok(Some(line)) => return Some(Ok(line.to_string())),
+                Ok(None) => (),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+// Entries of a tab separated file with an origin+pattern and 0/1 allowed in each line
+// patterns are normalized in the same way as the urls of the index
+fn tsv_entries(mut lines: Lines) -> impl Iterator<Item = Entry> {
+    std::iter::from_fn(move || loop {
+        let line = match lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        // parse tab separated lines
+        let parts: Vec<_> = line.split('\t').collect();
+        let allowed = match parts.get(1) {
+            Some(&"1") => true,
+            Some(&"0") => false,
+            _ => match lines.bad_record(&line, "Could not parse bool") {
+                Ok(()) => continue,
+                Err(e) => return Some(Err(e.into())),
+            },
+        };
+        return Some(Ok((url_key(parts[0]), allowed, 0)));
+    })
+}
+
+// Entries of the robots.txt in a warc2text JSONL file
+// each rule path is prefixed with the origin of the robots.txt url
+fn robots_entries(mut lines: Lines, parser: Arc<RobotsParser>) -> impl Iterator<Item = Entry> {
+    // groups are numbered across the file, robots.txt of the same origin do not share them
+    let mut num_groups = 0;
+    std::iter::from_fn(move || {
+        let line = match lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(vec![Err(e)]),
+        };
+        let entries: Vec<Entry> = match serde_json::from_str::<RobotsDoc>(&line) {
+            Err(e) => match lines.bad_record(&line, e) {
+                Ok(()) => Vec::new(),
+                Err(e) => vec![Err(e.into())],
+            },
+            // robots that do not disallow anything have no effect
+            Ok(doc) if !doc.p.to_lowercase().contains("disallow") => Vec::new(),
+            Ok(doc) => {
+                let origin = Url::parse(&doc.u).origin();
+                let mut entries = Vec::new();
+                for rules in parser.parse(&doc.p) {
+                    entries.extend(rules.into_iter().map(|rule| {
+                        Ok((format!("{origin}{}", rule.pattern), rule.allow, num_groups))
+                    }));
+                    num_groups += 1;
+                }
+                entries
+            }
+        };
+        Some(entries)
+    })
+    .flatten()
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let args = Args::parse();
+    let input = InputSource::new(&args.allowancefiles)?;
+    let allowancefiles = input.files().to_vec();
 
     let progstyle = ProgressStyle::default_bar();
-    let progbar = ProgressBar::new(args.allowancefiles.len() as u64)
+    let progbar = ProgressBar::new(allowancefiles.len() as u64)
         .with_style(progstyle)
         .with_finish(ProgressFinish::AndLeave);
 
     // let mmap = unsafe { Mmap::map(&fs::File::open(args.indexpath)?)? };
     let index = Arc::new(Set::new(fs::read(args.indexpath)?)?);
 
+    let errors = Arc::new(ErrorHandler::new(&args.errors)?);
     let (sender, receiver) = channel();
-    let shared_state = SharedState {
+    let banned = Arc::new(RwLock::new(HashMap::new()));
+    let mut shared_state = SharedState {
         index: index.clone(),
         sender: sender,
-        banned: Arc::new(RwLock::new(HashSet::new())),
+        banned: banned.clone(),
+        parser: match args.format {
+            InputFormat::Robots => Some(Arc::new(RobotsParser::new(&args.user_agents))),
+            InputFormat::Tsv => None,
+        },
+        fallback: args.origin_fallback,
+        origins: Arc::new(HashSet::new()),
+        max_disallowed: args.max_disallowed,
+        max_results: args.max_results,
+        disallow_banned: args.disallow_banned,
+        patterns: Arc::new(RwLock::new(HashMap::new())),
+        pattern_cache_size: args.pattern_cache_size,
+        errors: errors.clone(),
     };
 
+    // origins with robots.txt need to be known before applying the rules to other origins
+    if args.origin_fallback != OriginFallback::Exact {
+        info!("Collecting origins with robots.txt");
+        let origins = allowancefiles
+            .par_iter()
+            .map(|filename| file_origins(&shared_state, filename))
+            .try_reduce(HashSet::new, |mut a, b| {
+                a.extend(b);
+                Ok(a)
+            })
+            .map_err(|e| e.to_string())?;
+        info!("{} origins with robots.txt", origins.len());
+        shared_state.origins = Arc::new(origins);
+    }
+
     // process each file in parallel, sharing the same index
     let handler = std::thread::spawn(move || {
-        args.allowancefiles
+        let result = allowancefiles
             .par_iter()
             .progress_with(progbar)
-            .for_each_with(shared_state.clone(), |state, filename| {
-                process_file(state, &filename).unwrap();
-            });
+            .map_with(shared_state.clone(), |state, filename| {
+                process_file(state, filename)
+                    .map_err(|e| format!("Error processing '{filename}': {e}"))
+            })
+            .collect::<Result<(), String>>();
 
         // explicitly drop the shared state, so we make sure there are no remaining senders
         // after the par_iter finishes and therefore the receiver does not deadblock
         drop(shared_state);
+        result
     });
 
+    // urls for the FST need to be sorted and deduplicated first
+    // both outputs keep the format of the disallowed lists, without scheme and 'www.'
+    let mut sorter = args.output_fst.as_ref().map(|path| args.sort.sorter(path));
     while let Ok(line) = receiver.recv() {
-        println!("{}", line);
+        let key = disallowed_key(&line);
+        match &mut sorter {
+            Some(sorter) => sorter.push(key.to_string())?,
+            None => println!("{}", key),
+        }
+    }
+    handler.join().unwrap()?;
+    errors.finish()?;
+    if let (Some(sorter), Some(path)) = (sorter, &args.output_fst) {
+        info!("Writing FST of disallowed urls");
+        let num_urls = write_set(path, sorter.finish()?)?;
+        info!("{} disallowed urls", num_urls);
+    }
+
+    let banned = banned.read().unwrap();
+    if !banned.is_empty() {
+        warn!("{} origins banned", banned.len());
+    }
+    if let Some(filename) = &args.banned_report {
+        let mut report = BufWriter::new(fs::File::create(filename)?);
+        let mut origins: Vec<_> = banned.iter().collect();
+        origins.sort_by_key(|(origin, _)| *origin);
+        for (origin, ban) in origins {
+            writeln!(report, "{origin}\t{}\t{}", ban.rule, ban.num_results)?;
+        }
+        report.flush()?;
     }
-    handler.join().unwrap();
 
     Ok(())
 }
@@ -1,9 +1,6 @@
 use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::sync::mpsc::sync_channel;
-use std::sync::Arc;
-use std::thread;
+use std::io::{BufRead, BufWriter, Write};
 use std::time::Instant;
 
 use clap::Parser;
@@ -11,11 +8,14 @@ use env_logger::Env;
 use fst::Set;
 use log::info;
 use memmap2::Mmap;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use zstd::stream::read::Decoder;
+use rayon::prelude::*;
 use zstd::stream::write::Encoder;
 
+use monotextor_utils::input::{self, InputSource};
+use monotextor_utils::onerror::{BadRecord, ErrorArgs, ErrorHandler};
+use monotextor_utils::robots::{parse_timestamp, RobotsArgs, RobotsIndex};
+use monotextor_utils::splice::RawDocument;
+use monotextor_utils::url::{disallowed_key, url_key};
 use monotextor_utils::utils::memory_usage;
 
 #[derive(Parser)]
@@ -24,18 +24,55 @@ use monotextor_utils::utils::memory_usage;
     about = "Annotate JSONL documents with langid and/or robotstxt allowance"
 )]
 struct Args {
-    #[arg(help = "Path to robotstxt disallowed info with an FST index")]
-    disallowed_index: String,
-    #[arg(help = "Input jsonl zstd metadata file containing 'u' field for URLs")]
+    #[arg(
+        short,
+        long,
+        required_unless_present = "robots_index",
+        help = "Path to robotstxt disallowed info with an FST index"
+    )]
+    disallowed_index: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "disallowed_index",
+        help = "Evaluate each url with a robots-compile index of rules by origin"
+    )]
+    robots_index: Option<String>,
+    #[command(flatten)]
+    robots_args: RobotsArgs,
+    #[arg(help = "Input jsonl metadata file containing 'u' field for URLs, compressed or not")]
     input_file: String,
     #[arg(help = "Output jsonl zstd containing robotstxt annotations")]
     output_file: String,
+    #[arg(
+        long,
+        help = "Add the 'id' of the document to each annotation, to check the alignment"
+    )]
+    include_id: bool,
+    #[arg(
+        long,
+        default_value_t = 50000,
+        help = "Number of documents to be processed at a time"
+    )]
+    batch_size: usize,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Number of processing threads. By default, use all available cores"
+    )]
+    threads: usize,
+    #[arg(long, default_value_t = 3)]
+    compression_level: i32,
+    #[arg(long, default_value_t = 0)]
+    compression_threads: u32,
+    #[command(flatten)]
+    errors: ErrorArgs,
 }
 
-// Define a document struct
-#[derive(Serialize, Deserialize)]
-struct Document {
-    u: String,
+enum Index {
+    // FST of disallowed urls
+    Disallowed(Set<Mmap>),
+    // robots.txt rules by origin
+    Robots(RobotsIndex),
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
@@ -44,41 +81,128 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     info!("Started");
     let args = Args::parse();
 
-    let mmap = unsafe { Mmap::map(&fs::File::open(args.disallowed_index)?)? };
-    let index = Set::new(mmap)?;
-    // remove url http and ww prefix
-    let url_prefix_re = Arc::new(Regex::new(r"^(https?://)?(www\.)?(.*)$")?);
+    let index = match (&args.robots_index, &args.disallowed_index) {
+        (Some(path), _) => Index::Robots(RobotsIndex::open(path)?.with_args(&args.robots_args)),
+        (None, Some(path)) => {
+            let mmap = unsafe { Mmap::map(&fs::File::open(path)?)? };
+            Index::Disallowed(Set::new(mmap)?)
+        }
+        (None, None) => unreachable!("clap requires one of the indexes"),
+    };
 
-    let (sender, receiver) = sync_channel(50000);
-    let input_reader = BufReader::new(Decoder::new(File::open(args.input_file)?)?);
-    let mut output_writer =
-        BufWriter::new(Encoder::new(File::create(args.output_file)?, 3)?.auto_finish());
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build_global()?;
 
-    let read_thread = thread::spawn(move || {
-        for line_result in input_reader.split(b'\n') {
-            let line = line_result.unwrap();
-            sender.send(line).unwrap();
-        }
-    });
+    let errors = ErrorHandler::new(&args.errors)?;
+    let input = InputSource::from_files(vec![args.input_file.clone()]);
+    let mut encoder = Encoder::new(File::create(&args.output_file)?, args.compression_level)?;
+    encoder.multithread(args.compression_threads)?;
+    let mut output_writer = BufWriter::new(encoder);
 
-    while let Ok(line) = receiver.recv() {
-        let doc: Document = serde_json::from_slice(&line).expect("Error parsing JSON document");
-        let url = url_prefix_re
-            .captures(&doc.u)
-            .expect("Could not parse url")
-            .get(3)
-            .expect("Could not obtain capture group 3 for url")
-            .as_str();
-        if index.contains(url) {
-            output_writer.write(b"{\"allowed\": false}\n")?;
-        } else {
-            output_writer.write(b"{\"allowed\": true}\n")?;
+    let mut num_read = 0;
+    for batch in input.batches(args.batch_size) {
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                drop(output_writer);
+                fs::remove_file(&args.output_file)?;
+                errors.finish()?;
+                return Err(e.into());
+            }
+        };
+        num_read += batch.lines.len();
+        // process every batch in parallel, the output is kept in input order
+        let annotations = batch
+            .lines
+            .par_iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let line_num = batch.first_line + i;
+                // bad records still need an output line, to keep it aligned with the input
+                let Some(line_str) = errors.decode(&args.input_file, line_num, line)? else {
+                    return Ok(annotation(None, None, args.include_id));
+                };
+                // parse only the id, url and crawl time, the rest of the metadata is not needed
+                let doc = match RawDocument::parse(line_str) {
+                    Ok(doc) => doc,
+                    Err(e) => {
+                        errors.bad_record(&args.input_file, line_num, line, e)?;
+                        return Ok(annotation(None, None, args.include_id));
+                    }
+                };
+                let id: Option<String> = doc.get("id").and_then(Result::ok);
+                let u: String = match doc.get("u") {
+                    Some(Ok(u)) => u,
+                    Some(Err(e)) => {
+                        errors.bad_record(&args.input_file, line_num, line, e)?;
+                        return Ok(annotation(id.as_deref(), None, args.include_id));
+                    }
+                    None => {
+                        let e = "Document has no 'u' field";
+                        errors.bad_record(&args.input_file, line_num, line, e)?;
+                        return Ok(annotation(id.as_deref(), None, args.include_id));
+                    }
+                };
+                let allowed = match &index {
+                    Index::Disallowed(set) => !set.contains(disallowed_key(&url_key(&u))),
+                    Index::Robots(robots) => {
+                        let ts: Option<String> = doc.get("ts").and_then(Result::ok);
+                        robots.is_allowed(&u, ts.as_deref().and_then(parse_timestamp))
+                    }
+                };
+                Ok(annotation(id.as_deref(), Some(allowed), args.include_id))
+            })
+            .collect::<Result<Vec<String>, BadRecord>>();
+        let annotations = match annotations {
+            Ok(annotations) => annotations,
+            Err(e) => {
+                // an incomplete output is not aligned with the input, do not leave it
+                drop(output_writer);
+                fs::remove_file(&args.output_file)?;
+                errors.finish()?;
+                return Err(e.into());
+            }
+        };
+
+        for annotation in annotations {
+            output_writer.write_all(annotation.as_bytes())?;
+            output_writer.write_all(b"\n")?;
         }
     }
+    output_writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .finish()?;
+    errors.finish()?;
+
+    // the output has to be aligned with the input, check what has been written
+    let num_written = input::open(&args.output_file)?.split(b'\n').count();
+    if num_written != num_read {
+        return Err(format!(
+            "Number of output annotations is different than input documents: {} vs {}",
+            num_written, num_read
+        )
+        .into());
+    }
+    info!("{} documents annotated", num_written);
 
-    read_thread.join().unwrap();
     memory_usage();
     info!("Elapsed time: {:.2} s", now.elapsed().as_secs_f32());
     info!("Finished");
     Ok(())
 }
+
+// Annotation of a document, allowed is null for documents that could not be processed
+fn annotation(id: Option<&str>, allowed: Option<bool>, include_id: bool) -> String {
+    let allowed = match allowed {
+        Some(allowed) => allowed.to_string(),
+        None => String::from("null"),
+    };
+    if include_id {
+        let id = serde_json::to_string(&id).unwrap();
+        format!("{{\"id\": {id}, \"allowed\": {allowed}}}")
+    } else {
+        format!("{{\"allowed\": {allowed}}}")
+    }
+}
@@ -0,0 +1,104 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+// Progress of a long-running job, saved every time an output split is completed.
+// At that point everything that has been written belongs to finished splits,
+// so a job can be resumed by skipping the input up to `file_idx` and `line_offset`
+// and writing again from split `shard`, giving the same output as an uninterrupted run.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Checkpoint {
+    // input files of the job, to refuse resuming with a different input
+    pub files: Vec<String>,
+    // index of the input file where processing has to continue
+    pub file_idx: usize,
+    // number of lines of that file already processed
+    pub line_offset: usize,
+    // number of the output split to be written next
+    pub shard: usize,
+    // documents read and kept so far
+    pub num_read_docs: usize,
+    pub num_kept_docs: usize,
+    // number of hashes of the Bloom filter stored next to the checkpoint, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bloom_hashes: Option<u32>,
+}
+
+impl Checkpoint {
+    pub fn new(files: &[String]) -> Self {
+        Self {
+            files: files.to_vec(),
+            shard: 1,
+            ..Default::default()
+        }
+    }
+
+    // Load a checkpoint, None if it does not exist
+    pub fn load(path: &str) -> io::Result<Option<Self>> {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(path)?);
+        let checkpoint = serde_json::from_reader(reader)?;
+        Ok(Some(checkpoint))
+    }
+
+    // Load a checkpoint and check that it belongs to a job with the same input files
+    pub fn load_for(path: &str, files: &[String]) -> io::Result<Option<Self>> {
+        match Self::load(path)? {
+            Some(checkpoint) if checkpoint.files != files => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Checkpoint '{path}' was created with different input files"),
+            )),
+            other => Ok(other),
+        }
+    }
+
+    // Save the checkpoint atomically, writing to a temp file and renaming it,
+    // so a crash while saving leaves the previous checkpoint intact
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        write_atomic(path, |writer| {
+            serde_json::to_writer_pretty(&mut *writer, self)?;
+            writer.write_all(b"\n")
+        })
+    }
+
+    // Remove the checkpoint and its side files once the job is finished
+    pub fn remove(path: &str) -> io::Result<()> {
+        if let Some(checkpoint) = Self::load(path)? {
+            if checkpoint.bloom_hashes.is_some() {
+                remove_if_exists(&bloom_path(path, checkpoint.shard))?;
+            }
+        }
+        remove_if_exists(path)
+    }
+}
+
+// File where the Bloom filter state is stored for a given split
+// a different name is used for each split, so the checkpoint always points to a complete file
+pub fn bloom_path(path: &str, shard: usize) -> String {
+    format!("{path}.bloom.{shard}")
+}
+
+// Write a file to a temp name and rename it when it is complete
+// the temp name is unique for each process, in case several jobs write the same file
+pub fn write_atomic<F>(path: &str, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let tmp_path = format!("{path}.tmp.{}", std::process::id());
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    write(&mut writer)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+pub fn remove_if_exists(path: &str) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -1,166 +1,428 @@
 use gaoya::unionfind::UnionFind;
 use log::debug;
-use regex::Regex;
-use std::collections::HashMap;
-use std::fs::File;
 use std::hash::{BuildHasher, Hasher};
-use std::io::{BufRead, BufReader};
-use zstd::stream::read::Decoder;
+use std::io::{self, BufRead};
+
+use crate::checkpoint::Checkpoint;
+use crate::input::{self, InputSource};
+use crate::onerror::ErrorHandler;
+use crate::splice::append_raw_fields;
+use crate::split::Output;
+
+// Number of lines read at a time from the input
+const BATCH_SIZE: usize = 10_000;
+
+// Compact representation of the clusters, with only what is needed to filter the documents:
+// a bit for each document telling if it is kept (it is the root of its cluster)
+// and the size of the clusters that have more than one document.
+// This takes a fraction of the memory of the full parents array plus a map with every element.
+pub struct Clusters {
+    pub length: usize,
+    keep: Vec<u64>,
+    // (root, size) sorted by root, only for non-singleton clusters
+    sizes: Option<Vec<(usize, usize)>>,
+}
+
+impl Clusters {
+    // Read a cluster file, with one or more clusters arrays (e.g. one for each band) to be merged
+    // The arrays are parsed while reading, so the lines are never in memory.
+    // With a single array, the compact form is built directly from it.
+    // The full parents array is only needed to merge multiple arrays with a union find,
+    // and it is released before computing the cluster sizes.
+    pub fn read(filename: &str, with_sizes: bool) -> io::Result<Self> {
+        let mut numbers = NumberReader::new(input::open(filename)?);
+
+        // header containing the number of records
+        let length = numbers.header()?;
+        let mut keep = vec![0_u64; length.div_ceil(64)];
+        // parent of each document that is not kept, in document order
+        let mut roots = Vec::new();
+        numbers.array(length, |i, id| {
+            if i == id {
+                keep[i / 64] |= 1 << (i % 64);
+            } else {
+                roots.push(id);
+            }
+        })?;
+
+        // merge the next arrays, each one after its own header
+        let mut uf: Option<UnionFind> = None;
+        while numbers.skip_empty_lines()? {
+            let num_records = numbers.header()?;
+            if num_records != length {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Clusters arrays of different size: {num_records} vs {length}"),
+                ));
+            }
+            let uf = uf.get_or_insert_with(|| {
+                debug!("Merging multiple clusters arrays");
+                let mut uf = UnionFind::new(length);
+                // the roots are released once they are in the parents array
+                let mut roots = std::mem::take(&mut roots).into_iter();
+                for (i, parent) in uf.parents.iter_mut().enumerate() {
+                    if keep[i / 64] & (1 << (i % 64)) == 0 {
+                        *parent = roots.next().expect("Missing root of a document");
+                    }
+                }
+                uf
+            });
+            numbers.array(length, |i, id| {
+                if id != uf.parents[i] {
+                    uf.union(i, id);
+                }
+            })?;
+        }
+
+        // back to the compact form, keeping the roots in the same vector
+        if let Some(uf) = uf {
+            keep.fill(0);
+            let mut parents = uf.parents;
+            let mut num_roots = 0;
+            for i in 0..parents.len() {
+                let parent = parents[i];
+                if parent == i {
+                    keep[i / 64] |= 1 << (i % 64);
+                } else {
+                    parents[num_roots] = parent;
+                    num_roots += 1;
+                }
+            }
+            parents.truncate(num_roots);
+            parents.shrink_to_fit();
+            roots = parents;
+        }
+
+        // count how many elements has each cluster, the root and the ones pointing to it
+        let sizes = with_sizes.then(|| {
+            roots.sort_unstable();
+            roots
+                .chunk_by(|a, b| a == b)
+                .map(|chunk| (chunk[0], chunk.len() + 1))
+                .collect()
+        });
+        Ok(Self {
+            length,
+            keep,
+            sizes,
+        })
+    }
+
+    pub fn is_kept(&self, id: usize) -> bool {
+        self.keep[id / 64] & (1 << (id % 64)) != 0
+    }
+
+    // Size of the cluster of a kept document, None if sizes have not been computed
+    pub fn cluster_size(&self, id: usize) -> Option<usize> {
+        let sizes = self.sizes.as_ref()?;
+        match sizes.binary_search_by_key(&id, |(root, _)| *root) {
+            Ok(idx) => Some(sizes[idx].1),
+            Err(_) => Some(1),
+        }
+    }
+
+    pub fn num_kept(&self) -> usize {
+        self.keep.iter().map(|bits| bits.count_ones() as usize).sum()
+    }
+}
 
 pub struct DedupFilter {
     pub num_docs: usize,
     pub num_read_docs: usize,
     pub num_unique: usize,
     print_duplicates: bool,
-    pub uf: UnionFind,
-    cluster_sizes: Option<HashMap<usize, usize>>,
+    pub clusters: Clusters,
+    output: Output,
+    // path and state of the checkpoint, saved each time an output split is completed
+    checkpoint: Option<(String, Checkpoint)>,
+    file_idx: usize,
 }
 
 impl DedupFilter {
-    pub fn new(clusterfile: String, print_duplicates: bool, add_cluster_size: bool) -> Self {
-        let uf = Self::read_cluster_file(clusterfile);
-        let mut sizes = None;
-        if add_cluster_size {
-            sizes = Some(Self::compute_cluster_sizes(&uf));
-        }
+    pub fn new(
+        clusterfile: String,
+        print_duplicates: bool,
+        add_cluster_size: bool,
+        output: Output,
+    ) -> Self {
+        let clusters =
+            Clusters::read(&clusterfile, add_cluster_size).unwrap_or_else(|e| panic!("{}", e));
         Self {
-            num_docs: uf.length,
+            num_docs: clusters.length,
             num_read_docs: 0,
             num_unique: 0,
             print_duplicates: print_duplicates,
-            uf: uf,
-            cluster_sizes: sizes,
+            clusters,
+            output,
+            checkpoint: None,
+            file_idx: 0,
         }
     }
 
-    pub fn read_cluster_file(filename: String) -> UnionFind {
-        let file =
-            File::open(&filename).expect(format!("Error opening file '{filename}'").as_str());
-        let decoder = Decoder::new(file)
-            .expect(format!("Uncompressed or corrupted file '{filename}'").as_str());
-        let mut reader = BufReader::new(decoder);
-
-        // Read header containing the number of records
-        let mut line = String::new();
-        reader
-            .read_line(&mut line)
-            .expect("Error reading header of clusterfile");
-        line.pop();
-        let parts: Vec<&str> = line.split(&[' ', '\t']).collect();
-        let num_records: usize = parts[0]
-            .parse()
-            .expect(format!("Could not parse {}", parts[0]).as_str());
-
-        let regex_header = Regex::new(r#"^[0-9]+$"#).expect("Error creating regex");
-        let mut uf = UnionFind::new(num_records);
-
-        for (i, line_result) in reader.lines().enumerate() {
-            line = line_result.expect("Error reading line");
-            if regex_header.is_match(line.as_str()) {
-                continue;
-            }
+    // Filter all the input files, starting from the position of the checkpoint
+    // if `checkpoint_path` is given, the checkpoint is updated each time an output split is completed
+    // the output must be started at the split number of the checkpoint
+    // Files are read and decompressed in parallel by the input reader threads,
+    // but processed in input order, so the ids match the ones in the cluster file
+    pub fn filter_files(
+        &mut self,
+        input: &InputSource,
+        errors: &ErrorHandler,
+        checkpoint: Checkpoint,
+        checkpoint_path: Option<&str>,
+    ) -> io::Result<()> {
+        let (start_idx, skip_lines) = (checkpoint.file_idx, checkpoint.line_offset);
+        self.num_read_docs = checkpoint.num_read_docs;
+        self.num_unique = checkpoint.num_kept_docs;
+        self.checkpoint = checkpoint_path.map(|path| (path.to_string(), checkpoint));
 
-            // parse the line and add doc ids to the set
-            let parts = line.split(&[' ', '\t']);
-            for (j, p) in parts.enumerate() {
-                if p.is_empty() {
-                    continue;
-                }
-                let id: usize = p
-                    .parse()
-                    .expect(format!("Could not parse '{}' in line {}:", p, i).as_str());
-                if i == 0 {
-                    uf.parents[j] = id;
-                } else {
-                    if id == uf.parents[j] {
-                        continue;
-                    }
-                    uf.union(j, id);
-                }
-            }
+        for batch in input.batches_from(BATCH_SIZE, start_idx, skip_lines) {
+            let batch = batch?;
+            self.file_idx = batch.file_idx;
+            let filename = input.name(batch.file_idx);
+            self.filter_dups(filename, batch.first_line, &batch.lines, errors)?;
         }
-        drop(line);
-
-        uf
+        self.output.flush()
     }
 
-    // Count how many elements has each cluster
-    fn compute_cluster_sizes(uf: &UnionFind) -> HashMap<usize, usize> {
-        let mut num_clusters = 0;
-        for i in 0..uf.parents.len() {
-            if i != uf.parents[i] {
-                num_clusters += 1;
+    // Write a line to the output
+    // if the current split is full, start the next one and save the checkpoint
+    // with the position of the line, before writing it
+    fn write_line(&mut self, line_offset: usize, line: &str) -> io::Result<()> {
+        if let (Output::Split(writer), Some((path, checkpoint))) =
+            (&mut self.output, &mut self.checkpoint)
+        {
+            if writer.is_full() {
+                writer.rotate()?;
+                checkpoint.file_idx = self.file_idx;
+                checkpoint.line_offset = line_offset;
+                checkpoint.shard = writer.num_splits();
+                checkpoint.num_read_docs = self.num_read_docs;
+                checkpoint.num_kept_docs = self.num_unique;
+                checkpoint.save(path)?;
+                debug!("Saved checkpoint {:?}", checkpoint);
             }
         }
-        let mut sizes: HashMap<usize, usize> = HashMap::with_capacity(num_clusters);
+        self.output.write_line(line.as_bytes())
+    }
 
-        for i in uf.parents.iter() {
-            if let Some(x) = sizes.get_mut(&i) {
-                *x += 1;
-            } else {
-                sizes.insert(*i, 1);
-            }
-        }
+    // Finish the output, closing the last split
+    pub fn finish(self) -> io::Result<()> {
+        self.output.finish()
+    }
 
-        sizes
+    // Stop after an error, removing the unfinished split
+    // the completed ones are kept, so it can be resumed from the checkpoint
+    pub fn abort(self) -> io::Result<()> {
+        self.output.abort()
     }
 
-    // Read compressed JSONL and discard duplicates according to a UF parents array
+    // Discard duplicates in a batch of JSONL lines according to a UF parents array
+    // `first_line` is the line number in the file of the first line in the batch
     // Re-assign doc id with a unique num reference given
     // If duplicates is true, print only duplicates
-    pub fn filter_dups(&mut self, filename: &String) {
-        let file = File::open(filename).expect(format!("Error opening file '{filename}'").as_str());
-        let decoder = Decoder::new(file)
-            .expect(format!("Uncompressed or corrupted file '{filename}'").as_str());
-        let reader = BufReader::new(decoder);
+    // Bad records are handled according to the error policy, but they still count as documents
+    // so the ids are aligned with the ones in the cluster file
+    pub fn filter_dups(
+        &mut self,
+        filename: &str,
+        first_line: usize,
+        lines: &[Vec<u8>],
+        errors: &ErrorHandler,
+    ) -> io::Result<()> {
+        for (j, line_bytes) in lines.iter().enumerate() {
+            // number of lines before this one in the file
+            let i = first_line - 1 + j;
+            let Some(line) = errors.decode(filename, i + 1, line_bytes)? else {
+                self.num_read_docs += 1;
+                continue;
+            };
 
-        for line_result in reader.lines() {
-            let line = line_result.expect("Error reading line");
+            let id = self.num_read_docs;
+            if id >= self.clusters.length {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Input has more documents than the cluster file ({}), found at '{}' line {}",
+                        self.clusters.length,
+                        filename,
+                        i + 1
+                    ),
+                ));
+            }
 
             // Discard every document that it is not its own parent
             // That way, we keep documents that do not have known duplicates
             // and one from each set of duplicates (the uppermost parent)
             if self.print_duplicates {
-                if self.uf.parents[self.num_read_docs] != self.num_read_docs {
-                    println!("{}", line);
+                if !self.clusters.is_kept(id) {
+                    self.write_line(i, line)?;
                 }
                 self.num_read_docs += 1;
                 continue;
-            } else if self.uf.parents[self.num_read_docs] != self.num_read_docs {
-                debug!(
-                    "Discarding document {} in cluster {}",
-                    self.num_read_docs, self.uf.parents[self.num_read_docs]
-                );
+            } else if !self.clusters.is_kept(id) {
+                debug!("Discarding document {}", id);
                 self.num_read_docs += 1;
                 continue;
             }
 
-            if let Some(cluster_sizes) = &self.cluster_sizes {
-                let csize = cluster_sizes
-                    .get(&self.uf.parents[self.num_read_docs])
-                    .expect(
-                        format!(
-                            "Could not found cluster size for doc {} in cluster {}",
-                            self.num_read_docs, self.uf.parents[self.num_read_docs]
-                        )
-                        .as_str(),
-                    );
-
+            if let Some(csize) = self.clusters.cluster_size(id) {
                 // we do not parse the document, otherwise the speed reduces by a half
                 // so just doing a safe check that the end of the string is actually the
-                // end of the JSON, therefore we can print the line as is, omit the last char
-                // and add the cluster_size field
-                if line.as_bytes()[line.len() - 1] != b'}' {
-                    panic!("Wrong line ending");
+                // end of the JSON, and add the cluster_size field
+                let csize = csize.to_string();
+                match append_raw_fields(line, &[("cluster_size", &csize)]) {
+                    Some(spliced) => self.write_line(i, &spliced)?,
+                    None => {
+                        errors.bad_record(filename, i + 1, line_bytes, "Wrong line ending")?;
+                        self.num_read_docs += 1;
+                        continue;
+                    }
                 }
-                println!("{},\"cluster_size\":{}}}", &line[0..line.len() - 1], csize);
             } else {
-                println!("{}", line);
+                self.write_line(i, line)?;
             }
 
             self.num_read_docs += 1;
             self.num_unique += 1;
         }
+        Ok(())
+    }
+}
+
+// Parser of the numbers of a cluster file, one at a time
+struct NumberReader<R> {
+    reader: R,
+    // current line, starting at 1
+    line: usize,
+}
+
+enum Token {
+    Number(usize),
+    EndOfLine,
+    EndOfFile,
+}
+
+impl<R: BufRead> NumberReader<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, line: 1 }
+    }
+
+    fn error(&self, msg: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} in line {} of clusterfile", msg, self.line),
+        )
+    }
+
+    fn next(&mut self) -> io::Result<Token> {
+        let mut value: Option<usize> = None;
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(value.map_or(Token::EndOfFile, Token::Number));
+            }
+            let mut consumed = 0;
+            let mut token = None;
+            for &c in buf {
+                match c {
+                    b'0'..=b'9' => {
+                        let digit = (c - b'0') as usize;
+                        value = value
+                            .unwrap_or(0)
+                            .checked_mul(10)
+                            .and_then(|v| v.checked_add(digit));
+                        if value.is_none() {
+                            return Err(self.error("Number too large"));
+                        }
+                    }
+                    b' ' | b'\t' | b'\r' if value.is_some() => {
+                        token = value.map(Token::Number);
+                    }
+                    b' ' | b'\t' | b'\r' => {}
+                    // the end of line is returned after the number before it
+                    b'\n' if value.is_some() => {
+                        token = value.map(Token::Number);
+                        break;
+                    }
+                    b'\n' => {
+                        self.line += 1;
+                        token = Some(Token::EndOfLine);
+                    }
+                    _ => return Err(self.error(&format!("Unexpected character {:?}", c as char))),
+                }
+                consumed += 1;
+                if token.is_some() {
+                    break;
+                }
+            }
+            self.reader.consume(consumed);
+            if let Some(token) = token {
+                return Ok(token);
+            }
+        }
+    }
+
+    // Read a header line, the number of records followed by anything, which is ignored
+    fn header(&mut self) -> io::Result<usize> {
+        let Token::Number(num_records) = self.next()? else {
+            return Err(self.error("Could not parse header"));
+        };
+        self.skip_line()?;
+        Ok(num_records)
+    }
+
+    // Skip the rest of the current line, including the end of line
+    fn skip_line(&mut self) -> io::Result<()> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            match buf.iter().position(|&c| c == b'\n') {
+                Some(pos) => {
+                    self.reader.consume(pos + 1);
+                    self.line += 1;
+                    return Ok(());
+                }
+                None => {
+                    let len = buf.len();
+                    self.reader.consume(len);
+                }
+            }
+        }
+    }
+
+    // Skip empty lines, returns false at the end of the file
+    fn skip_empty_lines(&mut self) -> io::Result<bool> {
+        loop {
+            if self.reader.fill_buf()?.is_empty() {
+                return Ok(false);
+            }
+            if self.reader.fill_buf()?[0] != b'\n' {
+                return Ok(true);
+            }
+            self.reader.consume(1);
+            self.line += 1;
+        }
+    }
+
+    // Read an array line with the parent of each document
+    fn array(&mut self, length: usize, mut add: impl FnMut(usize, usize)) -> io::Result<()> {
+        let mut i = 0;
+        while let Token::Number(id) = self.next()? {
+            if i >= length {
+                return Err(self.error(&format!("More than {length} documents")));
+            } else if id >= length {
+                return Err(self.error(&format!("Document id {id} out of range")));
+            }
+            add(i, id);
+            i += 1;
+        }
+        if i != length {
+            return Err(self.error(&format!("Expected {length} documents, found {i}")));
+        }
+        Ok(())
     }
 }
 
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::pii::{char_to_byte_spans, Span};
+
+#[derive(Deserialize, Serialize)]
+pub struct DocumentText {
+    // Parse documents ignoring all fields but "text"
+    pub text: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Lang {
+    pub lang: Vec<String>,
+    pub prob: Vec<f32>,
+}
+
+// Document shared by all the tools
+// Only text is required, because documents may have different fields at each stage
+// and external contributions may lack some of the metadata.
+// Fields that are not known here are kept in `extra` and written back as they were,
+// so tools do not drop information added by other steps.
+#[derive(Serialize, Deserialize)]
+pub struct Document {
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub f: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub o: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rs: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub u: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub c: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub de: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(alias = "openlid-v2", skip_serializing_if = "Option::is_none")]
+    pub openlid_v2: Option<Lang>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub htmllang: Option<Vec<String>>,
+    #[serde(alias = "glotlid-v3", skip_serializing_if = "Option::is_none")]
+    pub glotlid_v3: Option<Lang>,
+    #[serde(alias = "openlid-v3", skip_serializing_if = "Option::is_none")]
+    pub openlid_v3: Option<Lang>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seg_langs_openlid_v3: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crawl_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster_size: Option<usize>,
+    // character offsets (code points), not bytes, see pii module
+    // kept as is, so a malformed value does not make the whole document unreadable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pii: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doc_scores: Option<Vec<f32>>,
+    #[serde(rename = "web-register", skip_serializing_if = "Option::is_none")]
+    pub web_register: Option<HashMap<String, f32>>,
+    // any other field, including the ones that can be null (e.g. xml, md)
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Document {
+    // Document id, or an empty string if it has none, for logging purposes
+    pub fn id_or_empty(&self) -> &str {
+        self.id.as_deref().unwrap_or("")
+    }
+
+    // PII spans in character offsets, None if the document has none
+    pub fn pii_spans(&self) -> Option<serde_json::Result<Vec<Span>>> {
+        self.pii.as_ref().map(Vec::<Span>::deserialize)
+    }
+
+    // PII spans converted to byte offsets, ready to slice the text
+    pub fn pii_byte_spans(&self) -> Result<Option<Vec<Span>>, Box<dyn Error>> {
+        match self.pii_spans() {
+            Some(spans) => Ok(Some(char_to_byte_spans(&self.text, &spans?)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optional_and_unknown_fields() {
+        let line = r#"{"text":"a","u":"http://a.com/","xml":null,"foo":{"bar":1}}"#;
+        let doc: Document = serde_json::from_str(line).unwrap();
+        assert_eq!(doc.u.as_deref(), Some("http://a.com/"));
+        assert_eq!(doc.id_or_empty(), "");
+        assert!(doc.ts.is_none());
+        assert_eq!(doc.extra.len(), 2);
+        let json = serde_json::to_string(&doc).unwrap();
+        let back: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, serde_json::from_str::<Value>(line).unwrap());
+
+        assert!(serde_json::from_str::<Document>(r#"{"u":"http://a.com/"}"#).is_err());
+    }
+
+    #[test]
+    fn pii_spans() {
+        let doc: Document = serde_json::from_str(r#"{"text":"añb c"}"#).unwrap();
+        assert!(doc.pii_spans().is_none());
+
+        let doc: Document = serde_json::from_str(r#"{"text":"añb c","pii":[[1,3]]}"#).unwrap();
+        assert_eq!(doc.pii_spans().unwrap().unwrap(), vec![(1, 3)]);
+        assert_eq!(doc.pii_byte_spans().unwrap(), Some(vec![(1, 4)]));
+
+        // a malformed value does not prevent reading the document
+        let doc: Document = serde_json::from_str(r#"{"text":"añb c","pii":"x"}"#).unwrap();
+        assert!(doc.pii_spans().unwrap().is_err());
+        assert!(doc.pii_byte_spans().is_err());
+    }
+}
@@ -0,0 +1,224 @@
+// External merge sort of strings that may not fit in memory, e.g. the urls of a collection
+// Strings are kept in memory until the buffer is full, then sorted and written
+// to a zstd compressed run in a temporary directory. The runs are merged at the end,
+// removing duplicates, so the output is ready to build an FST set.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use fst::SetBuilder;
+use log::debug;
+use parse_size::parse_size;
+use rayon::prelude::*;
+use zstd::stream::read::Decoder;
+use zstd::stream::write::Encoder;
+
+use crate::checkpoint::write_atomic;
+
+// Command line options for the external sort, to be flattened into each binary arguments
+#[derive(clap::Args)]
+pub struct SortArgs {
+    #[arg(
+        long,
+        help = "Directory for the temporary sorted runs. By default, the directory of the output"
+    )]
+    pub tmp_dir: Option<String>,
+    #[arg(long, default_value = "4GiB", value_parser = |s: &str| parse_size(s),
+          help = "Memory used to sort before writing a run to the temporary directory")]
+    pub sort_memory: u64,
+    #[arg(
+        long,
+        default_value_t = 3,
+        help = "zstd level of the temporary sorted runs"
+    )]
+    pub run_compression_level: i32,
+}
+
+impl SortArgs {
+    // Sorter for an output, with the temporary runs next to it if there is no tmp dir
+    pub fn sorter(&self, output: &str) -> ExternalSorter {
+        let tmp_dir = match &self.tmp_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => match Path::new(output).parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            },
+        };
+        ExternalSorter::new(
+            tmp_dir,
+            self.sort_memory as usize,
+            self.run_compression_level,
+        )
+    }
+}
+
+// Sorters in this process, to give unique names to their runs
+static NUM_SORTERS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct ExternalSorter {
+    tmp_dir: PathBuf,
+    id: usize,
+    max_memory: usize,
+    compression_level: i32,
+    buffer: Vec<String>,
+    // approximate memory used by the buffer
+    buffer_size: usize,
+    runs: Vec<PathBuf>,
+}
+
+impl ExternalSorter {
+    pub fn new(tmp_dir: PathBuf, max_memory: usize, compression_level: i32) -> Self {
+        Self {
+            tmp_dir,
+            id: NUM_SORTERS.fetch_add(1, Ordering::Relaxed),
+            max_memory,
+            compression_level,
+            buffer: Vec::new(),
+            buffer_size: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, value: String) -> io::Result<()> {
+        self.buffer_size += value.capacity() + mem::size_of::<String>();
+        self.buffer.push(value);
+        if self.buffer_size >= self.max_memory {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn sort_buffer(&mut self) {
+        self.buffer.par_sort_unstable();
+        self.buffer.dedup();
+    }
+
+    fn write_run(&mut self) -> io::Result<()> {
+        self.sort_buffer();
+        let path = self.tmp_dir.join(format!(
+            "sort.{}.{}.{}.zst",
+            std::process::id(),
+            self.id,
+            self.runs.len()
+        ));
+        debug!(
+            "Writing sorted run of {} values to '{}'",
+            self.buffer.len(),
+            path.display()
+        );
+        // the run is registered first, so it is removed on drop even if writing fails
+        self.runs.push(path.clone());
+        let mut writer =
+            BufWriter::new(Encoder::new(File::create(&path)?, self.compression_level)?);
+        for value in self.buffer.drain(..) {
+            writer.write_all(value.as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+        self.buffer_size = 0;
+        Ok(())
+    }
+
+    // Sorted values without duplicates
+    pub fn finish(mut self) -> io::Result<Sorted> {
+        if self.runs.is_empty() {
+            self.sort_buffer();
+            return Ok(Sorted {
+                source: Source::Memory(mem::take(&mut self.buffer).into_iter()),
+                last: None,
+                _runs: self,
+            });
+        }
+        if !self.buffer.is_empty() {
+            self.write_run()?;
+        }
+        let mut readers = Vec::with_capacity(self.runs.len());
+        let mut heap = BinaryHeap::with_capacity(self.runs.len());
+        for (i, path) in self.runs.iter().enumerate() {
+            let mut reader = BufReader::new(Decoder::new(File::open(path)?)?).lines();
+            if let Some(value) = reader.next() {
+                heap.push(Reverse((value?, i)));
+            }
+            readers.push(reader);
+        }
+        Ok(Sorted {
+            source: Source::Runs(heap, readers),
+            last: None,
+            _runs: self,
+        })
+    }
+}
+
+// Remove the runs when they are not needed anymore
+impl Drop for ExternalSorter {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+type RunReader = Lines<BufReader<Decoder<'static, BufReader<File>>>>;
+
+enum Source {
+    Memory(std::vec::IntoIter<String>),
+    // next value of each run and the readers of the runs
+    Runs(BinaryHeap<Reverse<(String, usize)>>, Vec<RunReader>),
+}
+
+// Iterator over the sorted values of an ExternalSorter
+pub struct Sorted {
+    source: Source,
+    last: Option<String>,
+    // keep the runs until the iterator is dropped
+    _runs: ExternalSorter,
+}
+
+impl Iterator for Sorted {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (heap, readers) = match &mut self.source {
+            // already without duplicates
+            Source::Memory(values) => return values.next().map(Ok),
+            Source::Runs(heap, readers) => (heap, readers),
+        };
+        while let Some(Reverse((value, i))) = heap.pop() {
+            match readers[i].next() {
+                Some(Ok(next)) => heap.push(Reverse((next, i))),
+                Some(Err(e)) => return Some(Err(e)),
+                None => (),
+            }
+            if self.last.as_ref() != Some(&value) {
+                self.last = Some(value.clone());
+                return Some(Ok(value));
+            }
+        }
+        None
+    }
+}
+
+// Write sorted values without duplicates to an FST set, return the number of values
+pub fn write_set(
+    path: &str,
+    values: impl Iterator<Item = io::Result<String>>,
+) -> io::Result<usize> {
+    let mut num_values = 0;
+    write_atomic(path, |writer| {
+        let mut builder = SetBuilder::new(writer).map_err(io::Error::other)?;
+        for value in values {
+            builder.insert(value?).map_err(io::Error::other)?;
+            num_values += 1;
+        }
+        builder.finish().map_err(io::Error::other)
+    })?;
+    Ok(num_values)
+}
@@ -1,21 +1,12 @@
 use gaoya::minhash::MinHashDeduper;
 use gaoya::unionfind::UnionFind;
-use itertools::Itertools;
 use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::sync::mpsc::sync_channel;
-use std::thread;
-use zstd::stream::read::Decoder;
+use std::io;
 
+use crate::document::DocumentText;
+use crate::input::InputSource;
 use crate::minhash_processor::{MinHashProcessor, Tokenization};
-
-#[derive(Deserialize, Serialize)]
-struct DocumentText {
-    // Parse documents ignoring all fields but "text"
-    pub text: String,
-}
+use crate::onerror::{BadRecord, ErrorHandler};
 
 pub struct Indexer {
     hasher: MinHashProcessor,
@@ -40,53 +31,53 @@ impl Indexer {
         }
     }
 
-    // Read one file, parse, hash and insert each document in the index
-    pub fn index_file(&mut self, filename: &String, global_id: &mut usize) {
-        // create bounded thread communication channel
-        let (sender, receiver) = sync_channel(1);
-
-        // Copy to send it to the thread
-        let batch_size = self.batch_size;
-        let new_filename = filename.clone();
-
-        // Spawn a thread to do the file reading and decompression
-        let read_thread = thread::spawn(move || {
-            // read zstd compressed input, iterate in chunks
-            let file = File::open(&new_filename)
-                .expect(format!("Error opening file '{new_filename}'").as_str());
-            let decoder = Decoder::new(file)
-                .expect(format!("Uncompressed or corrupted file '{new_filename}'").as_str());
-            let chunks = &BufReader::new(decoder).lines().chunks(batch_size);
-
-            for batch_result in chunks {
-                let batch: Vec<String> = batch_result
-                    .map(|line| line.expect("Error reading line"))
-                    .collect();
-                sender.send(batch).unwrap();
-            }
-        });
-
-        // Read batched lines sent from the thread and process them
-        while let Ok(batch) = receiver.recv() {
-            let signatures: Vec<_> = batch
+    // Read all the input files, parse, hash and insert each document in the index
+    // Files are read and decompressed in parallel by the input reader threads,
+    // but batches are processed in input order, so ids follow the order of the documents
+    // Bad records are handled according to the error policy, they consume an id
+    // but are not inserted, so ids are still aligned with the line numbers of the input
+    // Returns the number of documents read from each file
+    pub fn index_input(
+        &mut self,
+        input: &InputSource,
+        global_id: &mut usize,
+        errors: &ErrorHandler,
+    ) -> io::Result<Vec<usize>> {
+        let mut counts = vec![0; input.files().len()];
+        // Read batched lines sent from the reader threads and process them
+        for batch in input.batches(self.batch_size) {
+            let batch = batch?;
+            counts[batch.file_idx] += batch.lines.len();
+            let filename = input.name(batch.file_idx);
+            let line_num = batch.first_line;
+            let batch = batch.lines;
+            let signatures = batch
                 .par_iter()
-                .map(|line: &String| {
-                    let doc: DocumentText =
-                        serde_json::from_str(line.as_str()).expect("Error parsing JSON document");
-                    self.hasher.create_signature(&doc.text)
-                })
-                .collect();
+                .enumerate()
+                .map(
+                    |(i, line)| match serde_json::from_slice::<DocumentText>(line) {
+                        Ok(doc) => Ok(Some(self.hasher.create_signature(&doc.text))),
+                        Err(e) => {
+                            errors.bad_record(filename, line_num + i, line, e)?;
+                            Ok(None)
+                        }
+                    },
+                )
+                .collect::<Result<Vec<_>, BadRecord>>()?;
 
             // Enumerate all the documents, global id's
+            // skipping the ones that could not be parsed
             let new_id = *global_id + signatures.len();
-            let ids: Vec<usize> = (*global_id..new_id).collect();
+            let (ids, signatures): (Vec<usize>, Vec<_>) = (*global_id..new_id)
+                .zip(signatures)
+                .filter_map(|(id, signature)| signature.map(|s| (id, s)))
+                .unzip();
 
             // insert into index in parallel
             self.index.par_bulk_insert(ids, signatures);
             *global_id = new_id;
         }
-
-        read_thread.join().unwrap();
+        Ok(counts)
     }
 
     pub fn find_clusters(&self) -> UnionFind {
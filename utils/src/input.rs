@@ -0,0 +1,553 @@
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use flate2::bufread::MultiGzDecoder;
+use glob::glob;
+use itertools::Itertools;
+use log::debug;
+use seahash::SeaHasher;
+use serde::{Deserialize, Serialize};
+use zstd::stream::read::Decoder;
+
+use crate::checkpoint::write_atomic;
+
+const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// extensions of the files read when a directory is given
+const DIR_EXTENSIONS: &[&str] = &[".jsonl", ".zst", ".gz"];
+
+// Open a file for reading, decompressing it if needed
+// the format (zstd, gzip or plain text) is detected by the magic bytes, not the extension
+// `-` reads from stdin
+pub fn open(path: &str) -> io::Result<Box<dyn BufRead + Send>> {
+    let inner: Box<dyn Read + Send> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Error opening file '{path}': {e}"))
+        })?)
+    };
+    let mut reader = BufReader::with_capacity(BUFFER_SIZE, inner);
+    let magic = reader.fill_buf()?;
+
+    if magic.starts_with(&ZSTD_MAGIC) {
+        debug!("Reading '{}' as zstd", path);
+        let decoder = Decoder::with_buffer(reader)?;
+        Ok(Box::new(BufReader::with_capacity(BUFFER_SIZE, decoder)))
+    } else if magic.starts_with(&GZIP_MAGIC) {
+        debug!("Reading '{}' as gzip", path);
+        let decoder = MultiGzDecoder::new(reader);
+        Ok(Box::new(BufReader::with_capacity(BUFFER_SIZE, decoder)))
+    } else {
+        debug!("Reading '{}' as plain text", path);
+        Ok(Box::new(reader))
+    }
+}
+
+// A batch of lines read from one of the inputs
+pub struct LineBatch {
+    pub file_idx: usize,
+    // line number of the first line in the batch, starting at 1
+    pub first_line: usize,
+    pub lines: Vec<Vec<u8>>,
+}
+
+// List of input files of a tool.
+// Each argument can be a file, a glob, a directory (the data files inside it, sorted by name),
+// `-` for stdin or `@list` for a file that contains one filename per line.
+// No arguments means reading from stdin.
+#[derive(Clone, Debug)]
+pub struct InputSource {
+    files: Vec<String>,
+    num_readers: usize,
+}
+
+impl InputSource {
+    pub fn new(args: &[String]) -> io::Result<Self> {
+        let mut files = Vec::new();
+        for arg in args {
+            expand(arg, &mut files)?;
+        }
+        if args.is_empty() {
+            files.push(String::from("-"));
+        }
+        debug!("Input files: {:?}", files);
+        Ok(Self::from_files(files))
+    }
+
+    // Input with an already expanded list of files
+    pub fn from_files(files: Vec<String>) -> Self {
+        Self {
+            files,
+            num_readers: 1,
+        }
+    }
+
+    // Read and decompress up to this number of files at the same time
+    // batches are still given in input order
+    pub fn with_readers(mut self, num_readers: usize) -> Self {
+        self.num_readers = num_readers.max(1);
+        self
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    // Name of an input, to be used in messages
+    pub fn name(&self, file_idx: usize) -> &str {
+        match self.files[file_idx].as_str() {
+            "-" => "<stdin>",
+            name => name,
+        }
+    }
+
+    // Read all the inputs in order, in batches of lines, from background threads
+    // lines are not decoded, so invalid UTF-8 can be handled as a bad record
+    pub fn batches(&self, batch_size: usize) -> Batches {
+        self.batches_from(batch_size, 0, 0)
+    }
+
+    // Same as `batches`, but starting at file `file_idx`, skipping its first `line_offset` lines
+    pub fn batches_from(&self, batch_size: usize, file_idx: usize, line_offset: usize) -> Batches {
+        // each file has its own channel, so the batches can be reassembled in order
+        // while other files are being read, readers block when the channel of their file is full
+        let mut senders = Vec::with_capacity(self.files.len());
+        let mut receivers = Vec::with_capacity(self.files.len());
+        for _ in 0..self.files.len() {
+            let (sender, receiver) = sync_channel(2);
+            senders.push(Some(sender));
+            receivers.push(receiver);
+        }
+        let senders = Arc::new(Mutex::new(senders));
+        let next_file = Arc::new(AtomicUsize::new(file_idx));
+        let finished: Arc<Vec<AtomicBool>> =
+            Arc::new((0..self.files.len()).map(|_| AtomicBool::new(false)).collect());
+
+        let mut handles = Vec::with_capacity(self.num_readers);
+        for _ in 0..self.num_readers.min(self.files.len()) {
+            let files = self.files.clone();
+            let senders = Arc::clone(&senders);
+            let next_file = Arc::clone(&next_file);
+            let finished = Arc::clone(&finished);
+            handles.push(thread::spawn(move || loop {
+                // files are taken in order, so the one that is needed next is always being read
+                let idx = next_file.fetch_add(1, Ordering::SeqCst);
+                if idx >= files.len() {
+                    return;
+                }
+                let sender = senders.lock().unwrap()[idx].take().unwrap();
+                let skip = if idx == file_idx { line_offset } else { 0 };
+                match read_file(&files[idx], idx, skip, batch_size, &sender) {
+                    Ok(true) => {}
+                    // the receiver has been dropped, nobody wants more batches
+                    Ok(false) => return,
+                    // the iterator returns the error and stops
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return;
+                    }
+                }
+                // mark the file as finished before closing its channel
+                // otherwise the receiver could see it closed and think the reader failed
+                finished[idx].store(true, Ordering::SeqCst);
+                drop(sender);
+            }));
+        }
+
+        Batches {
+            files: self.files.clone(),
+            receivers,
+            current: file_idx,
+            finished,
+            handles,
+        }
+    }
+}
+
+// Read one file sending batches of lines
+// the '\r' of CRLF line endings is removed
+// returns false if the receiver has been dropped
+fn read_file(
+    filename: &str,
+    file_idx: usize,
+    skip: usize,
+    batch_size: usize,
+    sender: &SyncSender<io::Result<LineBatch>>,
+) -> io::Result<bool> {
+    let reader = open(filename)?;
+    let mut first_line = skip + 1;
+    for chunk in &reader.split(b'\n').skip(skip).chunks(batch_size) {
+        let lines = chunk
+            .map(|line| {
+                let mut line = line?;
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                Ok(line)
+            })
+            .collect::<io::Result<Vec<Vec<u8>>>>()
+            .map_err(|e| io::Error::new(e.kind(), format!("Error reading '{filename}': {e}")))?;
+        let num_lines = lines.len();
+        let batch = LineBatch {
+            file_idx,
+            first_line,
+            lines,
+        };
+        if sender.send(Ok(batch)).is_err() {
+            return Ok(false);
+        }
+        first_line += num_lines;
+    }
+    Ok(true)
+}
+
+// Iterator over the batches sent by the reading threads, in input order
+// if a file can not be read (e.g. corrupted input), the error is returned and iteration stops
+// instead of silently skipping the rest of the file
+pub struct Batches {
+    files: Vec<String>,
+    receivers: Vec<Receiver<io::Result<LineBatch>>>,
+    current: usize,
+    finished: Arc<Vec<AtomicBool>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Iterator for Batches {
+    type Item = io::Result<LineBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.current < self.receivers.len() {
+            match self.receivers[self.current].recv() {
+                Ok(Ok(batch)) => return Some(Ok(batch)),
+                Ok(Err(e)) => {
+                    // readers of the next files see their channel closed and stop
+                    self.receivers.clear();
+                    self.handles.clear();
+                    return Some(Err(e));
+                }
+                Err(_) => {
+                    if !self.finished[self.current].load(Ordering::SeqCst) {
+                        panic!("Could not read the whole file '{}'", self.files[self.current]);
+                    }
+                    self.current += 1;
+                }
+            }
+        }
+        for handle in self.handles.drain(..) {
+            if let Err(e) = handle.join() {
+                std::panic::resume_unwind(e);
+            }
+        }
+        None
+    }
+}
+
+// Number of documents of an input file, with its size to detect files that have changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileCount {
+    pub path: String,
+    pub size: u64,
+    pub num_docs: Option<usize>,
+}
+
+// Description of the input of a job: the files, their sizes and number of documents.
+// Written by mhindex next to the clusters, so dedup can check that it is run
+// with the same input that was indexed, without reading all of it again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InputManifest {
+    pub num_docs: Option<usize>,
+    // seahash of the file names and sizes, in hex
+    pub files_hash: String,
+    pub files: Vec<FileCount>,
+}
+
+impl InputManifest {
+    // Describe the input files, with the number of documents of each one if known
+    pub fn new(input: &InputSource, counts: Option<&[usize]>) -> io::Result<Self> {
+        let mut files = Vec::with_capacity(input.files.len());
+        let mut hasher = SeaHasher::new();
+        for (idx, path) in input.files.iter().enumerate() {
+            // stdin can not be checked, size 0 is used
+            let size = match path.as_str() {
+                "-" => 0,
+                _ => fs::metadata(path)?.len(),
+            };
+            hasher.write(path.as_bytes());
+            hasher.write_u8(0);
+            hasher.write_u64(size);
+            files.push(FileCount {
+                path: path.clone(),
+                size,
+                num_docs: counts.map(|c| c[idx]),
+            });
+        }
+        Ok(Self {
+            num_docs: counts.map(|c| c.iter().sum()),
+            files_hash: format!("{:016x}", hasher.finish()),
+            files,
+        })
+    }
+
+    // Read all the input counting the documents (lines) of each file
+    pub fn count(input: &InputSource, batch_size: usize) -> io::Result<Self> {
+        let mut counts = vec![0; input.files.len()];
+        for batch in input.batches(batch_size) {
+            let batch = batch?;
+            counts[batch.file_idx] += batch.lines.len();
+        }
+        Self::new(input, Some(&counts))
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Error opening manifest '{path}': {e}"))
+        })?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        write_atomic(path, |writer| {
+            serde_json::to_writer_pretty(&mut *writer, self)?;
+            writer.write_all(b"\n")
+        })
+    }
+
+    // Check that another description corresponds to the same input files
+    pub fn check_files(&self, other: &Self) -> io::Result<()> {
+        if self.files_hash == other.files_hash {
+            return Ok(());
+        }
+        let msg = if self.files.len() != other.files.len() {
+            format!(
+                "different number of input files: {} vs {}",
+                self.files.len(),
+                other.files.len()
+            )
+        } else {
+            let (a, b) = self
+                .files
+                .iter()
+                .zip(&other.files)
+                .find(|(a, b)| a.path != b.path || a.size != b.size)
+                .expect("Input files with different hash but equal");
+            format!(
+                "input file '{}' ({} bytes) differs from '{}' ({} bytes)",
+                a.path, a.size, b.path, b.size
+            )
+        };
+        Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+}
+
+fn expand(arg: &str, files: &mut Vec<String>) -> io::Result<()> {
+    if arg == "-" {
+        files.push(arg.to_string());
+    } else if let Some(list) = arg.strip_prefix('@') {
+        let reader = BufReader::new(File::open(list)?);
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() {
+                files.push(line.to_string());
+            }
+        }
+    } else if Path::new(arg).is_dir() {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(arg)? {
+            let path = entry?.path();
+            let name = path.to_string_lossy().to_string();
+            if path.is_file() && DIR_EXTENSIONS.iter().any(|ext| name.ends_with(ext)) {
+                entries.push(name);
+            }
+        }
+        entries.sort();
+        files.append(&mut entries);
+    } else if Path::new(arg).exists() {
+        files.push(arg.to_string());
+    } else if arg.contains(['*', '?', '[']) {
+        let paths = glob(arg)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        if paths.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No files match '{arg}'"),
+            ));
+        }
+        files.extend(paths.iter().map(|p| p.to_string_lossy().to_string()));
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Input file '{arg}' does not exist"),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tempfile::tempdir;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_all(path: &Path) -> String {
+        let mut text = String::new();
+        open(path.to_str().unwrap())
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn open_by_magic_bytes() {
+        let dir = tempdir().unwrap();
+        let text = "{\"text\": \"a\"}\n{\"text\": \"b\"}\n";
+        // extensions that do not match the format
+        let zstd_file = dir.path().join("zstd.gz");
+        fs::write(&zstd_file, zstd::encode_all(text.as_bytes(), 0).unwrap()).unwrap();
+        let gzip_file = dir.path().join("gzip.jsonl");
+        let mut members = gzip(&text.as_bytes()[..14]);
+        members.extend(gzip(&text.as_bytes()[14..]));
+        fs::write(&gzip_file, members).unwrap();
+        let plain_file = dir.path().join("plain.zst");
+        fs::write(&plain_file, text).unwrap();
+        let empty_file = dir.path().join("empty");
+        fs::write(&empty_file, "").unwrap();
+
+        assert_eq!(read_all(&zstd_file), text);
+        assert_eq!(read_all(&gzip_file), text);
+        assert_eq!(read_all(&plain_file), text);
+        assert_eq!(read_all(&empty_file), "");
+        assert!(open(dir.path().join("missing").to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn expand_inputs() {
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        fs::create_dir(path("data")).unwrap();
+        for name in [
+            "data/b.jsonl",
+            "data/a.zst",
+            "data/c.gz",
+            "data/notes.txt",
+            "data/d.tsv",
+            "x.tsv",
+        ] {
+            fs::write(path(name), "").unwrap();
+        }
+        fs::create_dir(path("data/sub.jsonl")).unwrap();
+        fs::write(
+            path("list"),
+            format!("{}\n\n  {}\n", path("x.tsv"), path("data/c.gz")),
+        )
+        .unwrap();
+
+        let files = |args: &[String]| InputSource::new(args).unwrap().files().to_vec();
+        // data files in the directory sorted by name, no subdirectories
+        // other files can still be given by name or in a list
+        assert_eq!(
+            files(&[path("data")]),
+            [path("data/a.zst"), path("data/b.jsonl"), path("data/c.gz")]
+        );
+        assert_eq!(
+            files(&[path("data/*.*z*"), String::from("-")]),
+            [path("data/a.zst"), path("data/c.gz"), String::from("-")]
+        );
+        assert_eq!(
+            files(&[format!("@{}", path("list"))]),
+            [path("x.tsv"), path("data/c.gz")]
+        );
+        assert_eq!(files(&[]), ["-"]);
+
+        assert!(InputSource::new(&[path("data/*.json")]).is_err());
+        assert!(InputSource::new(&[path("missing.jsonl")]).is_err());
+    }
+
+    #[test]
+    fn batches_in_order() {
+        let dir = tempdir().unwrap();
+        let mut files = Vec::new();
+        let mut expected = Vec::new();
+        // files of different sizes, so readers finish out of order
+        for (idx, num_lines) in [50, 1, 0, 7, 20, 3].into_iter().enumerate() {
+            let lines: Vec<String> = (0..num_lines).map(|i| format!("{idx} {i}")).collect();
+            let path = dir.path().join(format!("{idx}.zst"));
+            let text = lines
+                .iter()
+                .map(|line| format!("{line}\n"))
+                .collect::<String>();
+            fs::write(&path, zstd::encode_all(text.as_bytes(), 0).unwrap()).unwrap();
+            files.push(path.to_string_lossy().to_string());
+            expected.extend(
+                lines
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, line)| (idx, i + 1, line)),
+            );
+        }
+
+        let read = |input: &InputSource, file_idx: usize, skip: usize| {
+            let mut lines = Vec::new();
+            for batch in input.batches_from(4, file_idx, skip) {
+                let batch = batch.unwrap();
+                assert!(batch.lines.len() <= 4);
+                for (i, line) in batch.lines.into_iter().enumerate() {
+                    let line = String::from_utf8(line).unwrap();
+                    lines.push((batch.file_idx, batch.first_line + i, line));
+                }
+            }
+            lines
+        };
+        for num_readers in [1, 3, 10] {
+            let input = InputSource::from_files(files.clone()).with_readers(num_readers);
+            assert_eq!(read(&input, 0, 0), expected);
+            // resuming in the middle of the fourth file
+            assert_eq!(read(&input, 3, 2), expected[53..]);
+        }
+    }
+
+    #[test]
+    fn batches_crlf_and_errors() {
+        let dir = tempdir().unwrap();
+        let crlf = dir.path().join("crlf.jsonl");
+        fs::write(&crlf, "a\r\nb\r\n\r\nc").unwrap();
+        // truncated zstd frame
+        let broken = dir.path().join("broken.zst");
+        let compressed = zstd::encode_all("d\n".repeat(1000).as_bytes(), 0).unwrap();
+        fs::write(&broken, &compressed[..compressed.len() / 2]).unwrap();
+        let files = [&crlf, &broken, &crlf]
+            .map(|path| path.to_string_lossy().to_string())
+            .to_vec();
+
+        let mut batches = InputSource::from_files(files).with_readers(2).batches(10);
+        let batch = batches.next().unwrap().unwrap();
+        assert_eq!(batch.lines, [&b"a"[..], b"b", b"", b"c"]);
+        // the lines decompressed before the error may be returned
+        let err = loop {
+            match batches.next().unwrap() {
+                Ok(batch) => assert_eq!(batch.file_idx, 1),
+                Err(e) => break e,
+            }
+        };
+        assert!(err.to_string().contains("broken.zst"), "{err}");
+        assert!(batches.next().is_none());
+    }
+}
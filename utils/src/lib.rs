@@ -1,13 +1,18 @@
-use serde::{Deserialize, Serialize};
-
+pub mod checkpoint;
 pub mod dedup;
+pub mod document;
+pub mod extsort;
 pub mod indexer;
+pub mod input;
 pub mod minhash_processor;
+pub mod onerror;
+pub mod pii;
+pub mod robots;
+pub mod secrets;
+pub mod splice;
 pub mod split;
+pub mod textfix;
+pub mod url;
 pub mod utils;
 
-#[derive(Deserialize, Serialize)]
-pub struct DocumentText {
-    // Parse documents ignoring all fields but "text"
-    pub text: String,
-}
+pub use document::DocumentText;
@@ -0,0 +1,155 @@
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use log::{error, info, warn};
+use zstd::stream::write::{AutoFinishEncoder, Encoder};
+
+// What to do when a record can't be processed (unparsable JSON, invalid UTF-8...)
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum OnError {
+    // Log the error and exit with non-zero status
+    Fail,
+    // Log the error, count it and continue
+    Skip,
+    // Same as skip, but also write the bad record to the quarantine file
+    Quarantine,
+}
+
+// Command line options for the error policy, to be flattened into each binary arguments
+#[derive(clap::Args)]
+pub struct ErrorArgs {
+    #[arg(value_enum, long, default_value_t = OnError::Fail,
+          help = "What to do with records that can't be parsed")]
+    pub on_error: OnError,
+    #[arg(
+        long,
+        required_if_eq("on_error", "quarantine"),
+        help = "zstd compressed file where bad records are written with --on-error=quarantine"
+    )]
+    pub quarantine_file: Option<String>,
+}
+
+// Bad record found with the fail policy, processing has to stop
+// it is returned instead of exiting, so callers can finish their outputs
+pub struct BadRecord {
+    pub source: String,
+    pub line_num: usize,
+    pub message: String,
+}
+
+impl Display for BadRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Bad record in {}:{}: {}, use --on-error to skip bad records",
+            self.source, self.line_num, self.message
+        )
+    }
+}
+
+// shown when returned from main
+impl fmt::Debug for BadRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for BadRecord {}
+
+// so it can be propagated from functions that return io errors
+impl From<BadRecord> for io::Error {
+    fn from(e: BadRecord) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+// Shared handler of bad records, can be used from multiple threads
+pub struct ErrorHandler {
+    policy: OnError,
+    num_errors: AtomicUsize,
+    quarantine: Option<Mutex<BufWriter<AutoFinishEncoder<'static, File>>>>,
+}
+
+impl ErrorHandler {
+    pub fn new(args: &ErrorArgs) -> io::Result<Self> {
+        let quarantine = match (&args.on_error, &args.quarantine_file) {
+            (OnError::Quarantine, Some(filename)) => Some(Mutex::new(BufWriter::new(
+                Encoder::new(File::create(filename)?, 3)?.auto_finish(),
+            ))),
+            _ => None,
+        };
+        Ok(Self {
+            policy: args.on_error,
+            num_errors: AtomicUsize::new(0),
+            quarantine,
+        })
+    }
+
+    // Handle a bad record found in `source` at line `line_num` (starting at 1)
+    // with the fail policy, the error is returned and the caller must stop
+    pub fn bad_record(
+        &self,
+        source: &str,
+        line_num: usize,
+        line: &[u8],
+        err: impl Display,
+    ) -> Result<(), BadRecord> {
+        if self.policy == OnError::Fail {
+            error!("Bad record in {}:{}: {}", source, line_num, err);
+            return Err(BadRecord {
+                source: source.to_string(),
+                line_num,
+                message: err.to_string(),
+            });
+        }
+
+        warn!("Bad record in {}:{}: {}", source, line_num, err);
+        self.num_errors.fetch_add(1, Ordering::Relaxed);
+        if let Some(quarantine) = &self.quarantine {
+            let mut writer = quarantine.lock().unwrap();
+            writer
+                .write_all(line)
+                .and_then(|_| writer.write_all(b"\n"))
+                .expect("Error writing to quarantine file");
+        }
+        Ok(())
+    }
+
+    // Decode a line as UTF-8, handling it as a bad record if it is not
+    // None if the record has to be skipped
+    pub fn decode<'a>(
+        &self,
+        source: &str,
+        line_num: usize,
+        line: &'a [u8],
+    ) -> Result<Option<&'a str>, BadRecord> {
+        match std::str::from_utf8(line) {
+            Ok(decoded) => Ok(Some(decoded)),
+            Err(e) => {
+                self.bad_record(source, line_num, line, e)?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn num_errors(&self) -> usize {
+        self.num_errors.load(Ordering::Relaxed)
+    }
+
+    // Flush the quarantine file and report the number of bad records
+    pub fn finish(&self) -> io::Result<()> {
+        if let Some(quarantine) = &self.quarantine {
+            quarantine.lock().unwrap().flush()?;
+        }
+        if self.num_errors() > 0 {
+            warn!("{} bad records skipped", self.num_errors());
+        } else {
+            info!("No bad records found");
+        }
+        Ok(())
+    }
+}
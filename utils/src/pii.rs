@@ -0,0 +1,136 @@
+use std::fmt;
+
+// PII spans are (start, end) ranges over the document text, with end exclusive.
+// They are computed by scripts/annotate.py on the text after monofixer, using Python
+// string positions, therefore the offsets are counted in Unicode code points (chars),
+// NOT in bytes. Rust slices strings by bytes, so spans must be converted with
+// `char_to_byte_spans` before doing `&text[start..end]`, otherwise non-ASCII text
+// will be mis-sliced or cause a panic.
+pub type Span = (usize, usize);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SpanError {
+    // start is greater than end
+    Reversed(Span),
+    // offset is beyond the end of the text
+    OutOfBounds(Span, usize),
+    // byte offset falls in the middle of a multi-byte character
+    NotCharBoundary(Span),
+}
+
+impl fmt::Display for SpanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpanError::Reversed(s) => write!(f, "span {:?} has start greater than end", s),
+            SpanError::OutOfBounds(s, len) => {
+                write!(f, "span {:?} is out of bounds for text of length {}", s, len)
+            }
+            SpanError::NotCharBoundary(s) => {
+                write!(f, "span {:?} does not lie on a character boundary", s)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SpanError {}
+
+// Convert code point spans to byte spans over the same text
+pub fn char_to_byte_spans(text: &str, spans: &[Span]) -> Result<Vec<Span>, SpanError> {
+    // for ASCII text both units are the same, avoid building the offsets table
+    if text.is_ascii() {
+        for &span in spans {
+            check_span(span, text.len())?;
+        }
+        return Ok(spans.to_vec());
+    }
+
+    // byte offset of each char, plus the end of the text
+    let offsets: Vec<usize> = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(text.len()))
+        .collect();
+    let num_chars = offsets.len() - 1;
+
+    let mut converted = Vec::with_capacity(spans.len());
+    for &span in spans {
+        check_span(span, num_chars)?;
+        converted.push((offsets[span.0], offsets[span.1]));
+    }
+    Ok(converted)
+}
+
+// Convert byte spans to code point spans over the same text
+pub fn byte_to_char_spans(text: &str, spans: &[Span]) -> Result<Vec<Span>, SpanError> {
+    let mut converted = Vec::with_capacity(spans.len());
+    for &span in spans {
+        check_span(span, text.len())?;
+        if !text.is_char_boundary(span.0) || !text.is_char_boundary(span.1) {
+            return Err(SpanError::NotCharBoundary(span));
+        }
+        let start = text[..span.0].chars().count();
+        let end = start + text[span.0..span.1].chars().count();
+        converted.push((start, end));
+    }
+    Ok(converted)
+}
+
+fn check_span(span: Span, len: usize) -> Result<(), SpanError> {
+    if span.0 > span.1 {
+        return Err(SpanError::Reversed(span));
+    }
+    if span.1 > len {
+        return Err(SpanError::OutOfBounds(span, len));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 'é' takes 2 bytes, '€' 3 and '😀' 4
+    const TEXT: &str = "café 10€ 😀 end";
+
+    #[test]
+    fn ascii_spans() {
+        let text = "call 555 1234";
+        assert_eq!(char_to_byte_spans(text, &[(5, 13)]), Ok(vec![(5, 13)]));
+        assert_eq!(byte_to_char_spans(text, &[(5, 13)]), Ok(vec![(5, 13)]));
+    }
+
+    #[test]
+    fn multibyte_spans() {
+        let chars = [(0, 4), (5, 8), (9, 10), (11, 14), (14, 14)];
+        let bytes = [(0, 5), (6, 11), (12, 16), (17, 20), (20, 20)];
+        assert_eq!(char_to_byte_spans(TEXT, &chars), Ok(bytes.to_vec()));
+        assert_eq!(byte_to_char_spans(TEXT, &bytes), Ok(chars.to_vec()));
+        assert_eq!(&TEXT[bytes[1].0..bytes[1].1], "10€");
+        assert_eq!(&TEXT[bytes[2].0..bytes[2].1], "😀");
+    }
+
+    #[test]
+    fn invalid_spans() {
+        assert_eq!(
+            char_to_byte_spans(TEXT, &[(4, 2)]),
+            Err(SpanError::Reversed((4, 2)))
+        );
+        // valid in bytes, but not in chars
+        assert_eq!(
+            char_to_byte_spans(TEXT, &[(0, 20)]),
+            Err(SpanError::OutOfBounds((0, 20), 14))
+        );
+        assert_eq!(
+            char_to_byte_spans(TEXT, &[(11, 15)]),
+            Err(SpanError::OutOfBounds((11, 15), 14))
+        );
+        assert_eq!(
+            byte_to_char_spans(TEXT, &[(0, 4)]),
+            Err(SpanError::NotCharBoundary((0, 4)))
+        );
+        assert_eq!(
+            byte_to_char_spans(TEXT, &[(0, 21)]),
+            Err(SpanError::OutOfBounds((0, 21), 20))
+        );
+    }
+}
@@ -0,0 +1,1183 @@
+// Parsing and evaluation of robots.txt files following RFC 9309
+// only the rules that apply to a set of user-agents are extracted
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use fst::{Automaton, Map, MapBuilder};
+use memmap2::Mmap;
+use serde::Deserialize;
+
+use crate::checkpoint::write_atomic;
+use crate::url::{normalize_path, origin, Url};
+
+// User-agents whose rules are followed by default
+pub const DEFAULT_AGENTS: &[&str] = &["ia_archiver", "ccbot", "*"];
+
+// Which robots.txt apply to the urls of an origin (scheme, host and port) without one
+// a robots.txt only applies to its exact origin, but crawls often have
+// the robots.txt of just one of the http/https or www/bare host variants of a site
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum OriginFallback {
+    // Only the robots.txt of the exact origin
+    Exact,
+    // The robots.txt of the same host with the other scheme (http/https)
+    Scheme,
+    // The robots.txt of the same scheme with or without 'www.'
+    Www,
+    // Any of the above, or both changes at once
+    #[default]
+    Any,
+}
+
+impl OriginFallback {
+    // Origins whose robots.txt apply to a url, in order of preference
+    // the first one that has a robots.txt is used
+    pub fn candidates(self, url: &Url) -> Vec<String> {
+        let other_scheme = match url.scheme.as_str() {
+            "http" => Some("https"),
+            "https" => Some("http"),
+            _ => None,
+        }
+        .filter(|_| matches!(self, Self::Scheme | Self::Any));
+        let other_host = match url.host.strip_prefix("www.") {
+            Some(host) => host.to_string(),
+            None => format!("www.{}", url.host),
+        };
+        let www = matches!(self, Self::Www | Self::Any);
+
+        let mut candidates = vec![url.origin()];
+        if let Some(scheme) = other_scheme {
+            candidates.push(origin(scheme, &url.host, url.port));
+        }
+        if www {
+            candidates.push(origin(&url.scheme, &other_host, url.port));
+        }
+        if let (Some(scheme), true) = (other_scheme, www) {
+            candidates.push(origin(scheme, &other_host, url.port));
+        }
+        candidates
+    }
+}
+
+// Which robots.txt of an origin apply to a document, depending on the time it was crawled
+// a url is disallowed if any of the robots.txt that apply disallows it
+// robots.txt without fetch time apply to every document,
+// and all the robots.txt of the origin apply if the crawl time of the document is not known
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum TimePolicy {
+    // All of them, regardless of the time
+    All,
+    // The one fetched closest in time to the crawl of the document
+    #[default]
+    Closest,
+    // The ones fetched within a time window around the crawl of the document,
+    // or the closest one if there is none
+    Window,
+}
+
+// Command line options for the evaluation of urls with a RobotsIndex,
+// to be flattened into each binary arguments
+#[derive(clap::Args)]
+pub struct RobotsArgs {
+    #[arg(value_enum, long, default_value_t = OriginFallback::Any,
+          help = "Variants of the url origin whose robots.txt is used if it has none")]
+    pub origin_fallback: OriginFallback,
+    #[arg(value_enum, long, default_value_t = TimePolicy::Closest,
+          help = "Which robots.txt of an origin apply to a document, depending on its crawl time")]
+    pub time_policy: TimePolicy,
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "Days before and after the crawl of a document for --time-policy=window"
+    )]
+    pub time_window: u64,
+}
+
+// An allow or disallow rule of a robots.txt
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rule {
+    pub allow: bool,
+    // path pattern, always starting with '/' or '*', percent-encoding normalized
+    pub pattern: String,
+}
+
+// A robots.txt file as given by warc2text --robots-process in JSONL format
+#[derive(Deserialize)]
+pub struct RobotsDoc {
+    // url of the robots.txt
+    pub u: String,
+    // body of the robots.txt
+    pub p: String,
+    // time it was fetched
+    #[serde(default)]
+    pub ts: Option<String>,
+}
+
+// Lines that are relevant to rule grouping
+enum Line<'a> {
+    UserAgent(&'a str),
+    // rules that can't be used (e.g. empty) still end the list of user-agents of a group
+    Rule(Option<Rule>),
+    Other,
+}
+
+// Extracts the rules of a robots.txt that apply to a list of user-agents
+pub struct RobotsParser {
+    // lowercased product tokens, without '*'
+    agents: Vec<String>,
+    // whether the rules for any user-agent ('*') are followed
+    any_agent: bool,
+}
+
+impl RobotsParser {
+    pub fn new<S: AsRef<str>>(agents: &[S]) -> Self {
+        let agents: Vec<String> = agents
+            .iter()
+            .map(|a| a.as_ref().trim().to_lowercase())
+            .filter(|a| !a.is_empty())
+            .collect();
+        Self {
+            any_agent: agents.iter().any(|a| a == "*"),
+            agents: agents.into_iter().filter(|a| a != "*").collect(),
+        }
+    }
+
+    // Parse the body of a robots.txt and return the rules of each of our user-agents
+    // Following RFC 9309, a group is one or more consecutive user-agent lines
+    // followed by rules, and all the groups that match an agent are merged.
+    // Agents without a group of their own follow the '*' group, which is also returned
+    // if '*' is in the list of agents. The rules of each agent are kept apart,
+    // the most specific rule is chosen among them and a url is disallowed
+    // if any agent is disallowed. Agents with the same rules are returned once.
+    pub fn parse(&self, body: &str) -> Vec<Vec<Rule>> {
+        let body = body.strip_prefix('\u{feff}').unwrap_or(body);
+
+        // rules of each of our agents, None if there is no group for it
+        let mut specific: Vec<Option<Vec<Rule>>> = vec![None; self.agents.len()];
+        let mut any = Vec::new();
+        // agents of the current group, as indexes of our agents, and whether it has '*'
+        let mut in_agents = false;
+        let (mut group, mut is_any) = (Vec::new(), false);
+
+        for line in body.split(['\n', '\r']) {
+            match parse_line(line) {
+                Line::UserAgent(agent) => {
+                    // a user-agent after rules starts a new group
+                    if !in_agents {
+                        (group, is_any) = (Vec::new(), false);
+                        in_agents = true;
+                    }
+                    if agent == "*" {
+                        is_any = true;
+                    } else if let Some(idx) = self.position(agent) {
+                        specific[idx].get_or_insert_with(Vec::new);
+                        group.push(idx);
+                    }
+                }
+                Line::Rule(rule) => {
+                    in_agents = false;
+                    let Some(rule) = rule else {
+                        continue;
+                    };
+                    for &idx in &group {
+                        specific[idx].as_mut().unwrap().push(rule.clone());
+                    }
+                    if is_any {
+                        any.push(rule);
+                    }
+                }
+                Line::Other => {}
+            }
+        }
+
+        let mut rule_sets: Vec<Vec<Rule>> = specific
+            .into_iter()
+            .map(|rules| rules.unwrap_or_else(|| any.clone()))
+            .collect();
+        if self.any_agent || rule_sets.is_empty() {
+            rule_sets.push(any);
+        }
+        for rules in &mut rule_sets {
+            rules.sort();
+            rules.dedup();
+        }
+        rule_sets.sort();
+        rule_sets.dedup();
+        rule_sets
+    }
+
+    // Compare the product token of a user-agent line with our agents, case insensitive
+    // the version and anything after it is ignored (e.g. 'CCBot/2.0')
+    fn position(&self, agent: &str) -> Option<usize> {
+        let token = agent
+            .split(['/', ' ', '\t'])
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+        self.agents.iter().position(|a| *a == token)
+    }
+}
+
+impl Default for RobotsParser {
+    fn default() -> Self {
+        Self::new(DEFAULT_AGENTS)
+    }
+}
+
+// Parse a line as 'key: value', ignoring comments and surrounding whitespace
+fn parse_line(line: &str) -> Line<'_> {
+    let line = match line.find('#') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+    let Some((key, value)) = line.split_once(':') else {
+        return Line::Other;
+    };
+    let value = value.trim();
+    let key = key.trim();
+
+    if key.eq_ignore_ascii_case("user-agent") {
+        if value.is_empty() {
+            return Line::Other;
+        }
+        return Line::UserAgent(value);
+    }
+    let allow = if key.eq_ignore_ascii_case("allow") {
+        true
+    } else if key.eq_ignore_ascii_case("disallow") {
+        false
+    } else {
+        return Line::Other;
+    };
+
+    // an empty rule does not match anything,
+    // patterns not starting with '/' or '*' are not valid paths
+    if !value.starts_with(['/', '*']) {
+        return Line::Rule(None);
+    }
+    // whitespace is not allowed in a path, ignore what is after it
+    let pattern = value.split([' ', '\t']).next().unwrap_or(value);
+    Line::Rule(Some(Rule {
+        allow,
+        pattern: normalize_path(pattern),
+    }))
+}
+
+// Check if a path pattern matches a path
+// '*' matches any sequence of characters and '$' at the end anchors the end of the path,
+// otherwise the pattern matches any path that starts with it
+pub fn pattern_matches(pattern: &[u8], path: &[u8]) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix(b"$") {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    // positions in the path that can be reached matching the pattern so far, sorted
+    let mut positions = vec![0];
+    for &c in pattern {
+        if c == b'*' {
+            positions = (positions[0]..=path.len()).collect();
+        } else {
+            positions.retain_mut(|pos| {
+                let matches = *pos < path.len() && path[*pos] == c;
+                *pos += 1;
+                matches
+            });
+        }
+        if positions.is_empty() {
+            return false;
+        }
+    }
+    !anchored || positions.last() == Some(&path.len())
+}
+
+// A path pattern prepared to search the urls that match it in an FST, see PatternAutomaton
+// the pattern is split in the literal parts between '*',
+// the first one must be at the start of the path and the rest are searched in order
+pub struct CompiledPattern {
+    // the first part may be empty, the rest are not
+    parts: Vec<Vec<u8>>,
+    // KMP failure function of each part after the first, to search them
+    failures: Vec<Vec<usize>>,
+    // the last part must be at the end of the path
+    anchored: bool,
+}
+
+impl CompiledPattern {
+    pub fn new(pattern: &str) -> Self {
+        let pattern = pattern.as_bytes();
+        // '*$' at the end is the same as no anchor
+        let (pattern, anchored) = match pattern.strip_suffix(b"$") {
+            Some(stripped) => (stripped, !stripped.ends_with(b"*")),
+            None => (pattern, false),
+        };
+        // paths always start with '/', a leading '*' can not match anything before it
+        let pattern = match pattern.first() {
+            Some(b'*') => [b"/", pattern].concat(),
+            _ => pattern.to_vec(),
+        };
+        let mut parts = pattern.split(|c| *c == b'*');
+        let first = parts.next().unwrap_or_default().to_vec();
+        let parts: Vec<Vec<u8>> = std::iter::once(first)
+            .chain(parts.filter(|part| !part.is_empty()).map(<[u8]>::to_vec))
+            .collect();
+        let failures = parts.iter().skip(1).map(|part| failure(part)).collect();
+        Self {
+            parts,
+            failures,
+            anchored,
+        }
+    }
+
+    // Whether the pattern has no wildcards, so it is a prefix (or the exact path if anchored)
+    pub fn is_literal(&self) -> bool {
+        self.parts.len() == 1
+    }
+
+    // The pattern without wildcards and anchor
+    pub fn prefix(&self) -> &[u8] {
+        &self.parts[0]
+    }
+
+    pub fn is_anchored(&self) -> bool {
+        self.anchored
+    }
+}
+
+// KMP failure function, the length of the longest proper prefix of part[..=i]
+// that is also a suffix of it
+fn failure(part: &[u8]) -> Vec<usize> {
+    let mut failure = vec![0; part.len()];
+    let mut len = 0;
+    for i in 1..part.len() {
+        while len > 0 && part[i] != part[len] {
+            len = failure[len - 1];
+        }
+        if part[i] == part[len] {
+            len += 1;
+        }
+        failure[i] = len;
+    }
+    failure
+}
+
+// Automaton that matches the urls of an origin with a pattern, to search them in an FST
+// parts of the pattern are matched at their first occurrence,
+// which is enough for patterns that only have '*' wildcards.
+// The state is the part being matched and how many bytes of it are matched,
+// the origin is matched as a prefix of the first part.
+pub struct PatternAutomaton<'a> {
+    origin: &'a [u8],
+    pattern: &'a CompiledPattern,
+}
+
+impl<'a> PatternAutomaton<'a> {
+    pub fn new(origin: &'a str, pattern: &'a CompiledPattern) -> Self {
+        Self {
+            origin: origin.as_bytes(),
+            pattern,
+        }
+    }
+
+    fn part_len(&self, part: usize) -> usize {
+        match part {
+            0 => self.origin.len() + self.pattern.parts[0].len(),
+            _ => self.pattern.parts[part].len(),
+        }
+    }
+
+    fn is_last(&self, part: usize) -> bool {
+        part + 1 == self.pattern.parts.len()
+    }
+
+    // Move to the next part if the current one is matched
+    fn advance(&self, part: usize, matched: usize) -> Option<(usize, usize)> {
+        if matched == self.part_len(part) && !self.is_last(part) {
+            Some((part + 1, 0))
+        } else {
+            Some((part, matched))
+        }
+    }
+}
+
+impl Automaton for PatternAutomaton<'_> {
+    type State = Option<(usize, usize)>;
+
+    fn start(&self) -> Self::State {
+        self.advance(0, 0)
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        state.is_some_and(|(part, matched)| self.is_last(part) && matched == self.part_len(part))
+    }
+
+    fn can_match(&self, state: &Self::State) -> bool {
+        state.is_some()
+    }
+
+    fn will_always_match(&self, state: &Self::State) -> bool {
+        !self.pattern.anchored && self.is_match(state)
+    }
+
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        let (part, mut matched) = (*state)?;
+        let complete = matched == self.part_len(part);
+        if complete && !self.pattern.anchored {
+            return Some((part, matched));
+        }
+
+        // the first part must be at the start
+        if part == 0 {
+            if complete {
+                return None;
+            }
+            let expected = match self.origin.get(matched) {
+                Some(c) => *c,
+                None => self.pattern.parts[0][matched - self.origin.len()],
+            };
+            return if byte == expected {
+                self.advance(0, matched + 1)
+            } else {
+                None
+            };
+        }
+
+        // search the part in the rest of the url, the last one keeps being searched
+        // after a match if it has to be at the end
+        let bytes = &self.pattern.parts[part];
+        let failure = &self.pattern.failures[part - 1];
+        if complete {
+            matched = failure[matched - 1];
+        }
+        while matched > 0 && bytes[matched] != byte {
+            matched = failure[matched - 1];
+        }
+        if bytes[matched] == byte {
+            matched += 1;
+        }
+        self.advance(part, matched)
+    }
+}
+
+// Evaluate a path against a set of rules
+// the most specific (longest) matching rule is applied, allow wins if they are equally long
+pub fn is_allowed<'a>(rules: impl IntoIterator<Item = (bool, &'a [u8])>, path: &str) -> bool {
+    let (mut longest_allow, mut longest_disallow) = (None, None);
+    for (allow, pattern) in rules {
+        if !pattern_matches(pattern, path.as_bytes()) {
+            continue;
+        }
+        let longest = if allow {
+            &mut longest_allow
+        } else {
+            &mut longest_disallow
+        };
+        *longest = (*longest).max(Some(pattern.len()));
+    }
+    longest_allow >= longest_disallow
+}
+
+// Index of compiled robots.txt rules of a collection, to evaluate urls one by one
+// It is stored in two files: an FST map from origin to the offset of its robots.txt,
+// and a '.rules' file with a block for each origin. The block contains the number of
+// robots.txt of the origin (u32) and for each one: the time it was fetched (i64, seconds
+// since epoch, i64::MIN if unknown), the number of rules (u32), the size of the rules (u32)
+// and each rule: allow (u8), length (u32) and pattern.
+// Integers are little endian.
+pub struct RobotsIndex {
+    origins: Map<Mmap>,
+    rules: Mmap,
+    fallback: OriginFallback,
+    time_policy: TimePolicy,
+    // in seconds
+    time_window: u64,
+}
+
+impl RobotsIndex {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let origins = unsafe { Mmap::map(&File::open(path)?)? };
+        let rules = unsafe { Mmap::map(&File::open(rules_path(path))?)? };
+        Ok(Self {
+            origins: Map::new(origins)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            rules,
+            fallback: OriginFallback::default(),
+            time_policy: TimePolicy::default(),
+            time_window: 0,
+        })
+    }
+
+    // Configure the evaluation with the command line options
+    pub fn with_args(mut self, args: &RobotsArgs) -> Self {
+        self.fallback = args.origin_fallback;
+        self.time_policy = args.time_policy;
+        self.time_window = args.time_window * 24 * 3600;
+        self
+    }
+
+    // robots.txt of an origin, the time it was fetched and its rules for each one
+    pub fn robots(&self, origin: &str) -> Option<RobotsIter<'_>> {
+        let offset = self.origins.get(origin)? as usize;
+        let num_robots = read_u32(&self.rules, offset) as usize;
+        Some(RobotsIter {
+            data: &self.rules[offset + 4..],
+            remaining: num_robots,
+        })
+    }
+
+    // Whether a url crawled at a time (seconds since epoch) is allowed
+    // by the robots.txt of its origin, or the fallback one
+    // urls without any robots.txt are allowed
+    pub fn is_allowed(&self, url: &str, ts: Option<i64>) -> bool {
+        let url = Url::parse(url);
+        let robots = self
+            .fallback
+            .candidates(&url)
+            .iter()
+            .find_map(|origin| self.robots(origin));
+        match robots {
+            Some(robots) => self
+                .select(robots.collect(), ts)
+                .into_iter()
+                .all(|rules| is_allowed(rules, &url.path)),
+            None => true,
+        }
+    }
+
+    // Rules of the robots.txt that apply to a document crawled at a time
+    fn select<'a>(
+        &self,
+        robots: Vec<(Option<i64>, RuleIter<'a>)>,
+        ts: Option<i64>,
+    ) -> Vec<RuleIter<'a>> {
+        let distance = |fetched: Option<i64>| Some(fetched?.abs_diff(ts?));
+        let closest = robots
+            .iter()
+            .filter_map(|(fetched, _)| distance(*fetched))
+            .min();
+        robots
+            .into_iter()
+            .filter(|(fetched, _)| match (self.time_policy, closest) {
+                // robots.txt without fetch time apply to every document
+                _ if fetched.is_none() => true,
+                // without crawl time, all of them apply
+                (TimePolicy::All, _) | (_, None) => true,
+                (TimePolicy::Closest, Some(closest)) => distance(*fetched) == Some(closest),
+                // the closest one if there is none in the window
+                (TimePolicy::Window, Some(closest)) => {
+                    distance(*fetched).is_some_and(|d| d <= self.time_window.max(closest))
+                }
+            })
+            .map(|(_, rules)| rules)
+            .collect()
+    }
+
+    pub fn num_origins(&self) -> usize {
+        self.origins.len()
+    }
+}
+
+fn rules_path(path: &str) -> String {
+    format!("{path}.rules")
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_i64(data: &[u8], offset: usize) -> i64 {
+    i64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// Iterator over the robots.txt of an origin in the index
+pub struct RobotsIter<'a> {
+    data: &'a [u8],
+    remaining: usize,
+}
+
+impl<'a> Iterator for RobotsIter<'a> {
+    type Item = (Option<i64>, RuleIter<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let fetched = Some(read_i64(self.data, 0)).filter(|ts| *ts != i64::MIN);
+        let num_rules = read_u32(self.data, 8) as usize;
+        let size = read_u32(self.data, 12) as usize;
+        let rules = RuleIter {
+            data: &self.data[16..16 + size],
+            remaining: num_rules,
+        };
+        self.data = &self.data[16 + size..];
+        Some((fetched, rules))
+    }
+}
+
+// Iterator over the rules of a robots.txt in the index
+pub struct RuleIter<'a> {
+    data: &'a [u8],
+    remaining: usize,
+}
+
+impl<'a> Iterator for RuleIter<'a> {
+    type Item = (bool, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let allow = self.data[0] == 1;
+        let len = read_u32(self.data, 1) as usize;
+        let pattern = &self.data[5..5 + len];
+        self.data = &self.data[5 + len..];
+        Some((allow, pattern))
+    }
+}
+
+// Rules of a user-agent in a robots.txt and the time it was fetched, if known
+type FetchedRules = (Option<i64>, Vec<Rule>);
+
+// Collects the robots.txt of each origin and writes a RobotsIndex
+// each set of rules is stored as a robots.txt of its own, a url is allowed
+// only if all the ones that apply allow it
+#[derive(Default)]
+pub struct RobotsIndexBuilder {
+    origins: BTreeMap<String, Vec<FetchedRules>>,
+}
+
+impl RobotsIndexBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Add the rules of each user-agent of a robots.txt, as given by RobotsParser
+    // a robots.txt without rules is kept, so it is still chosen by its fetch time
+    // and the origin is not evaluated with a fallback
+    pub fn add(&mut self, origin: &str, fetched: Option<i64>, rule_sets: Vec<Vec<Rule>>) {
+        let robots = self.origins.entry(origin.to_string()).or_default();
+        let rule_sets = if rule_sets.is_empty() {
+            vec![Vec::new()]
+        } else {
+            rule_sets
+        };
+        for mut rules in rule_sets {
+            rules.sort();
+            rules.dedup();
+            let rules = (fetched, rules);
+            if !robots.contains(&rules) {
+                robots.push(rules);
+            }
+        }
+    }
+
+    pub fn num_origins(&self) -> usize {
+        self.origins.len()
+    }
+
+    pub fn write(self, path: &str) -> io::Result<()> {
+        let mut offsets = Vec::with_capacity(self.origins.len());
+        write_atomic(&rules_path(path), |writer| {
+            let mut offset = 0;
+            for (origin, mut robots) in self.origins {
+                robots.sort_by_key(|(fetched, _)| *fetched);
+                offsets.push((origin, offset));
+                writer.write_all(&(robots.len() as u32).to_le_bytes())?;
+                offset += 4;
+                for (fetched, rules) in robots {
+                    let size: usize = rules.iter().map(|rule| 5 + rule.pattern.len()).sum();
+                    writer.write_all(&fetched.unwrap_or(i64::MIN).to_le_bytes())?;
+                    writer.write_all(&(rules.len() as u32).to_le_bytes())?;
+                    writer.write_all(&(size as u32).to_le_bytes())?;
+                    for rule in rules {
+                        writer.write_all(&[rule.allow as u8])?;
+                        writer.write_all(&(rule.pattern.len() as u32).to_le_bytes())?;
+                        writer.write_all(rule.pattern.as_bytes())?;
+                    }
+                    offset += 16 + size as u64;
+                }
+            }
+            Ok(())
+        })?;
+
+        write_atomic(path, |writer| {
+            let mut builder = MapBuilder::new(writer).map_err(io::Error::other)?;
+            for (origin, offset) in offsets {
+                builder.insert(origin, offset).map_err(io::Error::other)?;
+            }
+            builder.finish().map_err(io::Error::other)
+        })
+    }
+}
+
+// Seconds since epoch of a UTC timestamp like '2021-05-09T10:26:25Z', the format of 'ts' fields
+// None if it can not be parsed
+pub fn parse_timestamp(ts: &str) -> Option<i64> {
+    let ts = ts.trim().trim_end_matches('Z');
+    let (date, time) = ts.split_once(['T', ' '])?;
+    let date: Vec<i64> = date
+        .split('-')
+        .map(|x| x.parse().ok())
+        .collect::<Option<_>>()?;
+    // fractions of second are ignored
+    let time = time.split('.').next()?;
+    let time: Vec<i64> = time
+        .split(':')
+        .map(|x| x.parse().ok())
+        .collect::<Option<_>>()?;
+    let (&[year, month, day], &[hour, minute, second]) = (date.as_slice(), time.as_slice()) else {
+        return None;
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !(0..24).contains(&hour)
+        || !(0..60).contains(&minute)
+        || !(0..=60).contains(&second)
+    {
+        return None;
+    }
+
+    // days since epoch of the civil date
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::url::split_key;
+    use fst::{IntoStreamer, Set, Streamer};
+    use tempfile::tempdir;
+
+    // Whether a path is allowed by a robots.txt for the default agents
+    fn allowed(body: &str, path: &str) -> bool {
+        RobotsParser::default().parse(body).iter().all(|rules| {
+            is_allowed(
+                rules
+                    .iter()
+                    .map(|rule| (rule.allow, rule.pattern.as_bytes())),
+                path,
+            )
+        })
+    }
+
+    #[test]
+    fn agents_evaluated_separately() {
+        // '*' is one of our agents, its disallow still applies
+        let body = "User-agent: *\nDisallow: /\n\nUser-agent: ccbot\nAllow: /\n";
+        assert!(!allowed(body, "/x"));
+        assert_eq!(RobotsParser::default().parse(body).len(), 2);
+        // without '*' in the list, only the specific group applies
+        let rules = RobotsParser::new(&["ccbot"]).parse(body);
+        assert_eq!(
+            rules,
+            [[Rule {
+                allow: true,
+                pattern: String::from("/")
+            }]]
+        );
+
+        // agents without a group of their own follow '*', and equal rules are given once
+        let body = "User-agent: *\nDisallow: /a\n";
+        assert_eq!(RobotsParser::default().parse(body).len(), 1);
+    }
+
+    #[test]
+    fn longest_match_within_agent() {
+        let body = "User-agent: *\nAllow: /a/b\nDisallow: /a\n";
+        assert!(allowed(body, "/a/b"));
+        assert!(allowed(body, "/a/b/c"));
+        assert!(!allowed(body, "/a"));
+        assert!(!allowed(body, "/a/c"));
+        assert!(allowed(body, "/b"));
+    }
+
+    #[test]
+    fn group_parsing() {
+        // (robots.txt, path, allowed for the default agents)
+        let cases = [
+            ("User-agent: *\nDisallow: /a\n", "/a", false),
+            ("User-agent: *\nDisallow: /a\n", "/b", true),
+            ("\u{feff}User-agent: *\r\nDisallow: /a\r\n", "/a", false),
+            ("user-agent: *\ndisallow: /a # comment\n", "/a", false),
+            ("USER-AGENT: CCBot/2.0 (bot)\nDISALLOW: /a\n", "/a", false),
+            // groups of other agents do not apply
+            ("User-agent: Googlebot\nDisallow: /a\n", "/a", true),
+            ("User-agent: ccbot-extra\nDisallow: /a\n", "/a", true),
+            // consecutive user-agent lines share the group, also with blank lines between
+            (
+                "User-agent: other\nUser-agent: ccbot\nDisallow: /a\n",
+                "/a",
+                false,
+            ),
+            (
+                "User-agent: other\n\nUser-agent: ccbot\nDisallow: /a\n",
+                "/a",
+                false,
+            ),
+            // a user-agent after rules starts a new group
+            (
+                "User-agent: ccbot\nDisallow: /a\nUser-agent: other\nDisallow: /b\n",
+                "/b",
+                true,
+            ),
+            // groups of the same agent are merged
+            (
+                "User-agent: ccbot\nAllow: /a/b\n\nUser-agent: other\nDisallow: /\n\n\
+                 User-agent: ccbot\nDisallow: /a\n",
+                "/a/b",
+                true,
+            ),
+            // an empty or invalid rule ends the list of user-agents
+            (
+                "User-agent: *\nDisallow:\nUser-agent: other\nDisallow: /\n",
+                "/a",
+                true,
+            ),
+            (
+                "User-agent: *\nDisallow: a\nUser-agent: other\nDisallow: /\n",
+                "/a",
+                true,
+            ),
+            // rules without user-agent and other lines are ignored
+            (
+                "Disallow: /a\nUser-agent: *\nSitemap: /s.xml\nCrawl-delay: 1\n",
+                "/a",
+                true,
+            ),
+            // whitespace ends the path
+            ("User-agent: *\nDisallow: /a b\n", "/a", false),
+            // paths are normalized as the urls
+            ("User-agent: *\nDisallow: /%7ea/ü\n", "/~a/%C3%BC", false),
+        ];
+        for (body, path, expected) in cases {
+            assert_eq!(allowed(body, path), expected, "{body:?} {path}");
+        }
+
+        // each agent follows its own group or '*'
+        let parser = RobotsParser::new(&["ccbot", "ia_archiver"]);
+        let body = "User-agent: ccbot\nDisallow: /a\n\nUser-agent: *\nDisallow: /b\n";
+        let rule_sets = parser.parse(body);
+        let patterns: Vec<Vec<&str>> = rule_sets
+            .iter()
+            .map(|rules| rules.iter().map(|rule| rule.pattern.as_str()).collect())
+            .collect();
+        assert_eq!(patterns, [["/a"], ["/b"]]);
+        assert!(RobotsParser::new(&["ccbot"])
+            .parse("")
+            .iter()
+            .all(Vec::is_empty));
+    }
+
+    #[test]
+    fn rfc_precedence() {
+        // (rules, path, allowed) examples of RFC 9309 section 2.2.2 and 5.2
+        let cases = [
+            (
+                "Allow: /example/page/\nDisallow: /example/page/disallowed.gif",
+                "/example/page/",
+                true,
+            ),
+            (
+                "Allow: /example/page/\nDisallow: /example/page/disallowed.gif",
+                "/example/page/disallowed.gif",
+                false,
+            ),
+            ("Allow: /p\nDisallow: /", "/page", true),
+            ("Allow: /folder\nDisallow: /folder", "/folder/page", true),
+            ("Allow: /page\nDisallow: /*.htm", "/page.htm", false),
+            ("Allow: /$\nDisallow: /", "/", true),
+            ("Allow: /$\nDisallow: /", "/page.htm", false),
+            ("Disallow: /", "/robots.txt", false),
+            ("Disallow:", "/page", true),
+        ];
+        for (rules, path, expected) in cases {
+            let body = format!("User-agent: *\n{rules}\n");
+            assert_eq!(allowed(&body, path), expected, "{rules:?} {path}");
+        }
+    }
+
+    #[test]
+    fn patterns() {
+        // (pattern, matching paths, not matching paths)
+        let cases = [
+            (
+                "/fish",
+                &[
+                    "/fish",
+                    "/fish.html",
+                    "/fish/salmon.html",
+                    "/fishheads",
+                    "/fish.php?id=1",
+                ][..],
+                &["/Fish.asp", "/catfish", "/?id=fish", "/"][..],
+            ),
+            (
+                "/fish*",
+                &["/fish", "/fish.html", "/fishheads"],
+                &["/Fish.asp", "/catfish"],
+            ),
+            (
+                "/fish/",
+                &["/fish/", "/fish/?id=1", "/fish/salmon.htm"],
+                &["/fish", "/fish.html"],
+            ),
+            (
+                "/*.php",
+                &[
+                    "/index.php",
+                    "/folder/index.php?a",
+                    "/any.php.file.html",
+                    "/index.php/",
+                ],
+                &["/", "/windows.PHP"],
+            ),
+            (
+                "/*.php$",
+                &["/index.php", "/folder/index.php"],
+                &["/index.php?a", "/index.php/", "/index.php5", "/windows.PHP"],
+            ),
+            (
+                "/fish*.php",
+                &["/fish.php", "/fishheads/catfish.php?a"],
+                &["/Fish.PHP"],
+            ),
+            ("*.gif$", &["/a.gif", "/.gif"], &["/a.gif?x", "/gif"]),
+            ("/a*$", &["/a", "/abc"], &["/b"]),
+            ("/$", &["/"], &["/a"]),
+            ("/*aab", &["/aab", "/aaab", "/xaaaab"], &["/aba", "/ab"]),
+            (
+                "/*abab$",
+                &["/abab", "/ababab", "/aabab"],
+                &["/ababa", "/aba"],
+            ),
+        ];
+        for (pattern, matching, not_matching) in cases {
+            for path in matching {
+                assert!(
+                    pattern_matches(pattern.as_bytes(), path.as_bytes()),
+                    "{pattern} {path}"
+                );
+            }
+            for path in not_matching {
+                assert!(
+                    !pattern_matches(pattern.as_bytes(), path.as_bytes()),
+                    "{pattern} {path}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pattern_automaton() {
+        let origin = "https://a.com";
+        let paths = [
+            "/",
+            "/a",
+            "/aa",
+            "/aab",
+            "/aaab",
+            "/ab",
+            "/aba",
+            "/abab",
+            "/ababa",
+            "/ababab",
+            "/abaababab",
+            "/a/b/c.php",
+            "/a.php?x",
+            "/a.php",
+            "/b.phpphp",
+            "/fish",
+            "/fish/a.php",
+            "/x/abcabd",
+            "/abcabcabd",
+            "/%C3%BC",
+            "/a$",
+        ];
+        let mut keys: Vec<String> = paths.iter().map(|path| format!("{origin}{path}")).collect();
+        // urls of other origins that share a prefix
+        keys.push(String::from("https://a.com.org/a"));
+        keys.push(String::from("https://a.co/a"));
+        keys.sort();
+        let index = Set::from_iter(&keys).unwrap();
+
+        let patterns = [
+            "/",
+            "/a",
+            "/a$",
+            "/*",
+            "*",
+            "/*$",
+            "/*.php",
+            "/*.php$",
+            "/*php$",
+            "/a*b",
+            "/a*b$",
+            "/*aab",
+            "/*abab$",
+            "/*aba*ab$",
+            "/*abcabd",
+            "/fish*.php",
+            "*/c.php",
+            "/a**b",
+            "/*%C3%BC$",
+            "/a*$",
+        ];
+        for pattern in patterns {
+            let compiled = CompiledPattern::new(pattern);
+            let mut stream = index
+                .search(PatternAutomaton::new(origin, &compiled))
+                .into_stream();
+            let mut found = Vec::new();
+            while let Some(key) = stream.next() {
+                found.push(String::from_utf8(key.to_vec()).unwrap());
+            }
+            let expected: Vec<String> = keys
+                .iter()
+                .filter(|key| {
+                    let (key_origin, path) = split_key(key);
+                    key_origin == origin && pattern_matches(pattern.as_bytes(), path.as_bytes())
+                })
+                .cloned()
+                .collect();
+            assert_eq!(found, expected, "{pattern}");
+        }
+
+        let compiled = CompiledPattern::new("/a/b$");
+        assert!(compiled.is_literal() && compiled.is_anchored());
+        assert_eq!(compiled.prefix(), b"/a/b");
+        assert!(!CompiledPattern::new("/a*$").is_anchored());
+        assert_eq!(failure(b"abacabab"), [0, 0, 1, 0, 1, 2, 3, 2]);
+    }
+
+    #[test]
+    fn timestamps() {
+        let cases = [
+            ("1970-01-01T00:00:00Z", Some(0)),
+            ("2021-05-09T10:26:25Z", Some(1620555985)),
+            ("2021-05-09 10:26:25", Some(1620555985)),
+            ("2021-05-09T10:26:25.123Z", Some(1620555985)),
+            ("2000-02-29T12:00:00Z", Some(951825600)),
+            ("1969-12-31T23:59:59Z", Some(-1)),
+            ("2021-13-09T10:26:25Z", None),
+            ("2021-05-09T24:00:00Z", None),
+            ("2021-05-09", None),
+            ("20210509102625", None),
+            ("", None),
+        ];
+        for (ts, expected) in cases {
+            assert_eq!(parse_timestamp(ts), expected, "{ts}");
+        }
+    }
+
+    #[test]
+    fn origin_fallback() {
+        let url = Url::parse("https://www.a.com:8443/x");
+        let cases = [
+            (OriginFallback::Exact, &["https://www.a.com:8443"][..]),
+            (
+                OriginFallback::Scheme,
+                &["https://www.a.com:8443", "http://www.a.com:8443"],
+            ),
+            (
+                OriginFallback::Www,
+                &["https://www.a.com:8443", "https://a.com:8443"],
+            ),
+            (
+                OriginFallback::Any,
+                &[
+                    "https://www.a.com:8443",
+                    "http://www.a.com:8443",
+                    "https://a.com:8443",
+                    "http://a.com:8443",
+                ],
+            ),
+        ];
+        for (fallback, candidates) in cases {
+            assert_eq!(fallback.candidates(&url), candidates);
+        }
+        let url = Url::parse("ftp://a.com/x");
+        assert_eq!(
+            OriginFallback::Any.candidates(&url),
+            ["ftp://a.com", "ftp://www.a.com"]
+        );
+    }
+
+    #[test]
+    fn index_time_policies() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("robots.idx");
+        let path = path.to_str().unwrap();
+        let day = 86400;
+        let rules = |body: &str| RobotsParser::default().parse(body);
+        let mut builder = RobotsIndexBuilder::new();
+        builder.add(
+            "https://a.com",
+            Some(10 * day),
+            rules("User-agent: *\nDisallow: /a\n"),
+        );
+        builder.add(
+            "https://a.com",
+            Some(20 * day),
+            rules("User-agent: *\nDisallow: /b\n"),
+        );
+        builder.add(
+            "https://a.com",
+            None,
+            rules("User-agent: *\nDisallow: /c\n"),
+        );
+        // a robots.txt without rules is still chosen by its time
+        builder.add("https://a.com", Some(40 * day), Vec::new());
+        builder.add("https://b.com", None, Vec::new());
+        builder.write(path).unwrap();
+
+        let index = |time_policy, time_window| {
+            RobotsIndex::open(path).unwrap().with_args(&RobotsArgs {
+                origin_fallback: OriginFallback::Any,
+                time_policy,
+                time_window,
+            })
+        };
+        let urls = [
+            "https://a.com/a",
+            "https://a.com/b",
+            "https://a.com/c",
+            "https://a.com/d",
+        ];
+        // (policy, window in days, crawl day, allowed of each url)
+        let cases = [
+            (TimePolicy::Closest, 0, Some(9), [false, true, false, true]),
+            (TimePolicy::Closest, 0, Some(18), [true, false, false, true]),
+            (TimePolicy::Closest, 0, Some(50), [true, true, false, true]),
+            (TimePolicy::Closest, 0, None, [false, false, false, true]),
+            (TimePolicy::All, 0, Some(9), [false, false, false, true]),
+            (TimePolicy::Window, 5, Some(15), [false, false, false, true]),
+            (TimePolicy::Window, 2, Some(9), [false, true, false, true]),
+            // the closest one if there is none in the window
+            (TimePolicy::Window, 2, Some(14), [false, true, false, true]),
+        ];
+        for (policy, window, crawl_day, expected) in cases {
+            let index = index(policy, window);
+            let allowed: Vec<bool> = urls
+                .iter()
+                .map(|url| index.is_allowed(url, crawl_day.map(|d: i64| d * day)))
+                .collect();
+            assert_eq!(allowed, expected, "{policy:?} {window} {crawl_day:?}");
+        }
+
+        let index = index(TimePolicy::Closest, 0);
+        assert_eq!(index.num_origins(), 2);
+        // fallback to the other scheme, but not if the origin has a robots.txt
+        assert!(!index.is_allowed("http://a.com/c", None));
+        assert!(index.is_allowed("https://www.b.com/c", None));
+        assert!(index.is_allowed("https://c.com/c", None));
+    }
+}
@@ -0,0 +1,315 @@
+use std::fs;
+use std::io::{self, BufRead};
+
+use aho_corasick::AhoCorasick;
+use clap::ValueEnum;
+use regex::{Regex, RegexSet};
+
+// What to do with documents that contain secrets
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum SecretsAction {
+    // Discard the document
+    Drop,
+    // Keep the document with filter "secrets"
+    Annotate,
+}
+
+// Built-in detection rules, a name and a regex for each one
+// these are secrets that have a recognizable format on their own
+const RULES: &[(&str, &str)] = &[
+    (
+        "aws_access_key",
+        r"\b(?:AKIA|ASIA|AGPA|AIDA|AROA)[0-9A-Z]{16}\b",
+    ),
+    ("github_token", r"\bgh[pousr]_[A-Za-z0-9]{36,255}\b"),
+    ("github_pat", r"\bgithub_pat_[A-Za-z0-9_]{82}\b"),
+    ("gitlab_token", r"\bglpat-[A-Za-z0-9_\-]{20}\b"),
+    ("slack_token", r"\bxox[abprs]-[A-Za-z0-9\-]{10,72}\b"),
+    ("google_api_key", r"\bAIza[0-9A-Za-z_\-]{35}\b"),
+    ("stripe_key", r"\b(?:sk|rk)_live_[0-9a-zA-Z]{24,99}\b"),
+    (
+        "openai_key",
+        r"\bsk-(?:proj-)?[A-Za-z0-9_\-]{20,}T3BlbkFJ[A-Za-z0-9_\-]{20,}\b",
+    ),
+    (
+        "sendgrid_key",
+        r"\bSG\.[A-Za-z0-9_\-]{22}\.[A-Za-z0-9_\-]{43}\b",
+    ),
+    (
+        "private_key",
+        r"-----BEGIN (?:[A-Z0-9]+ )*PRIVATE KEY(?: BLOCK)?-----",
+    ),
+    (
+        "jwt",
+        r"\beyJ[A-Za-z0-9_\-]{10,}\.eyJ[A-Za-z0-9_\-]{10,}\.[A-Za-z0-9_\-]{10,}",
+    ),
+];
+
+// Name of the rule that looks for high entropy tokens assigned to a secret-like keyword
+pub const KEYWORD_RULE: &str = "keyword_entropy";
+
+// Minimum Shannon entropy (bits per byte) of a token next to a keyword to be considered a secret
+const MIN_ENTROPY: f64 = 3.5;
+
+// Minimum fraction of adjacent characters of a different class (lowercase, uppercase, digit...)
+// words, even in camel case or with a number (e.g. "MySecretPassword"), rarely change class,
+// random tokens do it in about 2 out of 3 characters
+const MIN_CLASS_CHANGES: f64 = 0.4;
+
+// What made a document match
+pub enum SecretMatch {
+    // index (line number, starting at 0) of the pattern in the secrets list
+    Literal(usize),
+    // name of the built-in rule
+    Rule(&'static str),
+}
+
+pub struct SecretsMatcher {
+    literals: Option<AhoCorasick>,
+    rules: Option<(RegexSet, Regex)>,
+}
+
+impl SecretsMatcher {
+    // Create a matcher with the built-in rules and/or a list of literal secrets (one per line)
+    pub fn new(
+        builtin_rules: bool,
+        secrets_list: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        let literals = match secrets_list {
+            Some(filename) => {
+                let file_read = io::BufReader::new(fs::File::open(filename)?);
+                let patterns = file_read.lines().collect::<io::Result<Vec<_>>>()?;
+                Some(AhoCorasick::new(&patterns)?)
+            }
+            None => None,
+        };
+
+        let rules = if builtin_rules {
+            let set = RegexSet::new(RULES.iter().map(|(_, re)| re))?;
+            // keyword followed by an assignment and a token, the token is checked for entropy
+            let keyword_re = Regex::new(
+                r#"(?i)(?:api[_\-]?key|apikey|secret|token|passw(?:or)?d|access[_\-]?key|auth)["']?\s*[:=]\s*["']?([A-Za-z0-9/+_\-=.]{16,128})"#,
+            )?;
+            Some((set, keyword_re))
+        } else {
+            None
+        };
+
+        Ok(Self { literals, rules })
+    }
+
+    // Return the first secret found in the text, literals are checked first
+    pub fn find(&self, text: &str) -> Option<SecretMatch> {
+        if let Some(matcher) = &self.literals {
+            if let Some(m) = matcher.find(text) {
+                return Some(SecretMatch::Literal(m.pattern().as_usize()));
+            }
+        }
+
+        if let Some((set, keyword_re)) = &self.rules {
+            if let Some(idx) = set.matches(text).iter().next() {
+                return Some(SecretMatch::Rule(RULES[idx].0));
+            }
+            for caps in keyword_re.captures_iter(text) {
+                if looks_random(&caps[1]) {
+                    return Some(SecretMatch::Rule(KEYWORD_RULE));
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.find(text).is_some()
+    }
+}
+
+// A token is random if it has high entropy and is not made of words
+fn looks_random(token: &str) -> bool {
+    shannon_entropy(token) >= MIN_ENTROPY && class_changes(token) >= MIN_CLASS_CHANGES
+}
+
+// Fraction of adjacent bytes that are of a different class
+fn class_changes(token: &str) -> f64 {
+    let class = |b: &u8| match b {
+        b'a'..=b'z' => 0,
+        b'A'..=b'Z' => 1,
+        b'0'..=b'9' => 2,
+        _ => 3,
+    };
+    let bytes = token.as_bytes();
+    if bytes.len() < 2 {
+        return 0.0;
+    }
+    let changes = bytes
+        .windows(2)
+        .filter(|pair| class(&pair[0]) != class(&pair[1]))
+        .count();
+    changes as f64 / (bytes.len() - 1) as f64
+}
+
+// Shannon entropy in bits per byte
+fn shannon_entropy(token: &str) -> f64 {
+    let mut counts = [0_usize; 256];
+    for b in token.bytes() {
+        counts[b as usize] += 1;
+    }
+    let len = token.len() as f64;
+    counts
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // secrets are built from parts, so this file is not flagged by secret scanners
+    fn rule(text: &str) -> Option<&'static str> {
+        let matcher = SecretsMatcher::new(true, None).unwrap();
+        match matcher.find(text)? {
+            SecretMatch::Rule(name) => Some(name),
+            SecretMatch::Literal(_) => None,
+        }
+    }
+
+    fn chars(n: usize) -> String {
+        "aB3dE5fG7hJ9kL1mN2pQ4rS6tU8vW0xYz"
+            .chars()
+            .cycle()
+            .take(n)
+            .collect()
+    }
+
+    #[test]
+    fn builtin_rules() {
+        let header = |kind: &str| format!("-----BEGIN {kind}-----");
+        let cases = [
+            (
+                "aws_access_key",
+                format!("AKIA{}", chars(16).to_uppercase()),
+                format!("AKIA{}", chars(15).to_uppercase()),
+            ),
+            (
+                "github_token",
+                format!("ghp_{}", chars(36)),
+                format!("ghp_{}", chars(35)),
+            ),
+            (
+                "github_pat",
+                format!("github_pat_{}", chars(82)),
+                format!("github_pat_{}", chars(81)),
+            ),
+            (
+                "gitlab_token",
+                format!("glpat-{}", chars(20)),
+                format!("glpat-{}", chars(19)),
+            ),
+            (
+                "slack_token",
+                format!("xoxb-{}", chars(24)),
+                format!("xoxb-{}", chars(9)),
+            ),
+            (
+                "google_api_key",
+                format!("AIza{}", chars(35)),
+                format!("AIza{}", chars(34)),
+            ),
+            (
+                "stripe_key",
+                format!("sk_live_{}", chars(24)),
+                format!("sk_test_{}", chars(24)),
+            ),
+            (
+                "openai_key",
+                format!("sk-{}T3BlbkFJ{}", chars(20), chars(20)),
+                format!("sk-{}", chars(48)),
+            ),
+            (
+                "sendgrid_key",
+                format!("SG.{}.{}", chars(22), chars(43)),
+                format!("SG.{}.{}", chars(21), chars(43)),
+            ),
+            (
+                "private_key",
+                header("RSA PRIVATE KEY"),
+                header("PUBLIC KEY"),
+            ),
+            (
+                "private_key",
+                header("PGP PRIVATE KEY BLOCK"),
+                header("CERTIFICATE"),
+            ),
+            (
+                "jwt",
+                format!("eyJ{}.eyJ{}.{}", chars(12), chars(30), chars(43)),
+                format!("eyJ{}", chars(40)),
+            ),
+            (
+                "keyword_entropy",
+                format!("api_key = '{}'", chars(20)),
+                format!("password: {}", "a".repeat(20)),
+            ),
+        ];
+        for (name, positive, negative) in cases {
+            let text = format!("Some text with {positive} inside");
+            assert_eq!(rule(&text), Some(name), "{positive}");
+            let text = format!("Some text with {negative} inside");
+            assert_eq!(rule(&text), None, "{negative}");
+        }
+    }
+
+    #[test]
+    fn keyword_entropy() {
+        // a high entropy token needs the keyword and assignment
+        assert_eq!(rule(&format!("the value {} here", chars(24))), None);
+        assert_eq!(
+            rule(&format!("\"secret\": \"{}\"", chars(24))),
+            Some(KEYWORD_RULE)
+        );
+        // long words are not random
+        assert_eq!(rule("token=internationalization"), None);
+        assert!(shannon_entropy(&chars(32)) >= MIN_ENTROPY);
+        assert_eq!(class_changes("abcABC123"), 0.25);
+        assert_eq!(class_changes("aB3"), 1.0);
+        assert_eq!(class_changes("a"), 0.0);
+    }
+
+    #[test]
+    fn natural_language() {
+        // words after a keyword have high entropy but are not secrets
+        let texts = [
+            "Password: MySuperSecretPassword",
+            "token: Weihnachtsmarkt2023Berlin",
+            "auth=HTTPBasicAuthentication",
+            "Your password: CorrectHorseBatteryStaple",
+            "secret: Summer2024Holidays",
+            "api_key = SomeAccessTokenValue",
+            "Authentication: x-amz-security-token",
+            "Das Passwort: Sicherheitsrichtlinien",
+        ];
+        for text in texts {
+            assert_eq!(rule(text), None, "{text}");
+        }
+        assert_eq!(shannon_entropy("aaaa"), 0.0);
+        assert_eq!(shannon_entropy("abcd"), 2.0);
+    }
+
+    #[test]
+    fn literals() {
+        let mut list = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut list, b"first-secret\nsecond-secret\n").unwrap();
+        let matcher = SecretsMatcher::new(false, list.path().to_str()).unwrap();
+        assert!(matches!(
+            matcher.find("has second-secret"),
+            Some(SecretMatch::Literal(1))
+        ));
+        assert!(!matcher.is_match(&format!("AKIA{}", chars(16).to_uppercase())));
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+// Read and modify top-level fields of a JSON line without re-serializing the document.
+// Values are parsed as raw slices of the original line, so only the fields that are
+// requested get deserialized, and everything that is not modified is written back
+// byte-identical. Modified fields are replaced in place, new fields are appended at the end.
+pub struct RawDocument<'a> {
+    line: &'a str,
+    fields: HashMap<String, &'a RawValue>,
+    // key and raw JSON value of each modified or new field, in order of modification
+    edits: Vec<(String, String)>,
+}
+
+impl<'a> RawDocument<'a> {
+    pub fn parse(line: &'a str) -> serde_json::Result<Self> {
+        Ok(Self {
+            line,
+            fields: serde_json::from_str(line)?,
+            edits: Vec::new(),
+        })
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.fields.contains_key(key)
+    }
+
+    // Raw JSON value of a field, as found in the line
+    pub fn get_raw(&self, key: &str) -> Option<&'a RawValue> {
+        self.fields.get(key).copied()
+    }
+
+    // Deserialize the value of a field, None if the field does not exist
+    pub fn get<T: Deserialize<'a>>(&self, key: &str) -> Option<serde_json::Result<T>> {
+        self.get_raw(key).map(|raw| serde_json::from_str(raw.get()))
+    }
+
+    // Set the value of a field, replacing it if already exists
+    pub fn set<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> serde_json::Result<()> {
+        let raw = serde_json::to_string(value)?;
+        self.set_raw(key, raw);
+        Ok(())
+    }
+
+    // Set the value of a field with an already serialized JSON value
+    pub fn set_raw(&mut self, key: &str, raw: String) {
+        if let Some(edit) = self.edits.iter_mut().find(|(k, _)| k == key) {
+            edit.1 = raw;
+        } else {
+            self.edits.push((key.to_string(), raw));
+        }
+    }
+
+    // Write the line with all the modifications applied, without trailing newline
+    pub fn write_to(&self, out: &mut Vec<u8>) -> serde_json::Result<()> {
+        let base = self.line.as_ptr() as usize;
+        let mut replaced = Vec::new();
+        let mut appended = Vec::new();
+        for (key, value) in &self.edits {
+            match self.fields.get(key) {
+                Some(raw) => {
+                    // values are borrowed from the line, so the pointer gives the position
+                    let start = raw.get().as_ptr() as usize - base;
+                    replaced.push((start, start + raw.get().len(), value));
+                }
+                None => appended.push((key, value)),
+            }
+        }
+        replaced.sort_unstable_by_key(|(start, _, _)| *start);
+
+        let end = self.line.trim_end().len() - 1;
+        let mut pos = 0;
+        out.reserve(self.line.len());
+        for (start, stop, value) in replaced {
+            out.extend_from_slice(&self.line.as_bytes()[pos..start]);
+            out.extend_from_slice(value.as_bytes());
+            pos = stop;
+        }
+        out.extend_from_slice(&self.line.as_bytes()[pos..end]);
+        let mut empty = self.fields.is_empty();
+        for (key, value) in appended {
+            if !empty {
+                out.push(b',');
+            }
+            serde_json::to_writer(&mut *out, key)?;
+            out.push(b':');
+            out.extend_from_slice(value.as_bytes());
+            empty = false;
+        }
+        out.push(b'}');
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        let mut out = Vec::with_capacity(self.line.len());
+        self.write_to(&mut out)?;
+        // only valid UTF-8 has been written
+        Ok(String::from_utf8(out).expect("Spliced JSON is not valid UTF-8"))
+    }
+}
+
+// Append fields with already serialized values to a JSON line without parsing it
+// this only checks that the line ends with '}', so it is meant for trusted input
+// returns None if the line does not look like a JSON object
+// If the line may already have one of the fields (e.g. a re-run), it is parsed
+// to replace the value instead of adding a duplicated key
+pub fn append_raw_fields(line: &str, fields: &[(&str, &str)]) -> Option<String> {
+    if fields.iter().any(|(key, _)| line.contains(key)) {
+        let mut doc = RawDocument::parse(line).ok()?;
+        for (key, value) in fields {
+            doc.set_raw(key, value.to_string());
+        }
+        return doc.to_json().ok();
+    }
+
+    let trimmed = line.trim_end();
+    let body = trimmed.strip_suffix('}')?;
+    let mut out = String::with_capacity(line.len() + fields.len() * 32);
+    out.push_str(body);
+    let mut empty = body.trim() == "{";
+    for (key, value) in fields {
+        if !empty {
+            out.push(',');
+        }
+        out.push('"');
+        out.push_str(key);
+        out.push_str("\":");
+        out.push_str(value);
+        empty = false;
+    }
+    out.push('}');
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str =
+        r#"{"u": "https://a.com", "text":"caf\u00e9 \"x\"\n", "n": 1.50e3, "z": null,"a":[1, 2]}"#;
+
+    #[test]
+    fn untouched_fields_are_identical() {
+        let mut doc = RawDocument::parse(LINE).unwrap();
+        assert_eq!(
+            doc.get::<String>("text").unwrap().unwrap(),
+            "caf\u{e9} \"x\"\n"
+        );
+        assert_eq!(doc.to_json().unwrap(), LINE);
+
+        doc.set("n", &2).unwrap();
+        doc.set_raw("new", String::from("true"));
+        assert_eq!(
+            doc.to_json().unwrap(),
+            r#"{"u": "https://a.com", "text":"caf\u00e9 \"x\"\n", "n": 2, "z": null,"a":[1, 2],"new":true}"#
+        );
+    }
+
+    #[test]
+    fn append_fields() {
+        assert_eq!(
+            append_raw_fields(LINE, &[("cluster_size", "2")]).unwrap(),
+            r#"{"u": "https://a.com", "text":"caf\u00e9 \"x\"\n", "n": 1.50e3, "z": null,"a":[1, 2],"cluster_size":2}"#
+        );
+        assert_eq!(
+            append_raw_fields("{}", &[("k", "1")]).unwrap(),
+            r#"{"k":1}"#
+        );
+        assert_eq!(append_raw_fields(r#"{"k": 1"#, &[("j", "1")]), None);
+    }
+
+    #[test]
+    fn append_existing_field() {
+        let line = r#"{"cluster_size": 3, "text": "a"}"#;
+        assert_eq!(
+            append_raw_fields(line, &[("cluster_size", "2")]).unwrap(),
+            r#"{"cluster_size": 2, "text": "a"}"#
+        );
+        // only in the text, the field is still added
+        let line = r#"{"text": "cluster_size"}"#;
+        assert_eq!(
+            append_raw_fields(line, &[("cluster_size", "2")]).unwrap(),
+            r#"{"text": "cluster_size","cluster_size":2}"#
+        );
+    }
+}
@@ -1,18 +1,143 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use zstd::stream::write::{AutoFinishEncoder, Encoder};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
 
-type WBufEncoder = BufWriter<AutoFinishEncoder<'static, File>>;
+use log::warn;
+use regex::{Captures, Regex};
+use seahash::SeaHasher;
+use serde::{Deserialize, Serialize};
+use zstd::stream::write::Encoder;
 
+use crate::checkpoint::write_atomic;
+
+type WBufEncoder = BufWriter<Encoder<'static, File>>;
+
+// Description of a finished split
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SplitInfo {
+    pub index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub path: String,
+    pub num_docs: usize,
+    pub uncompressed_bytes: usize,
+    pub compressed_bytes: u64,
+    // seahash of the uncompressed content, in hex
+    pub hash: String,
+}
+
+// List of finished splits, rewritten every time a split is finished.
+// `complete` is only set when the writer has been finished,
+// so an output with an incomplete manifest must not be used downstream
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    pub complete: bool,
+    pub splits: Vec<SplitInfo>,
+}
+
+impl Manifest {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    fn save(&self, path: &str) -> io::Result<()> {
+        write_atomic(path, |writer| {
+            serde_json::to_writer_pretty(&mut *writer, self)?;
+            writer.write_all(b"\n")
+        })
+    }
+}
+
+// Names of the split files.
+// `{N}` is replaced by the split number, `{N:W}` by the split number zero-padded to W digits
+// and `{key}` by the split key, e.g. `batch_{N}.jsonl.zst` or `{key}_{N:4}.jsonl.zst`.
+// A plain prefix, without `{N}`, is named `{prefix}.{N}.zst`.
+pub struct NameTemplate {
+    template: String,
+    number_re: Regex,
+}
+
+impl NameTemplate {
+    pub fn new(prefix_or_template: &str) -> Self {
+        let template = if prefix_or_template.contains("{N") {
+            prefix_or_template.to_string()
+        } else {
+            format!("{}.{{N}}.zst", prefix_or_template)
+        };
+        Self {
+            template,
+            number_re: Regex::new(r"\{N(?::([0-9]+))?\}").expect("Error creating regex"),
+        }
+    }
+
+    pub fn has_key(&self) -> bool {
+        self.template.contains("{key}")
+    }
+
+    pub fn render(&self, idx: usize, key: &str) -> String {
+        let name = self.template.replace("{key}", key);
+        self.number_re
+            .replace_all(&name, |caps: &Captures| match caps.get(1) {
+                Some(width) => format!("{:0width$}", idx, width = width.as_str().parse().unwrap()),
+                None => idx.to_string(),
+            })
+            .into_owned()
+    }
+
+    // The manifest is named after the fixed part at the beginning of the template,
+    // e.g. `batch.manifest.json` for `batch_{N}.jsonl.zst`
+    pub fn manifest_path(&self) -> String {
+        let stem = &self.template[..self.template.find('{').unwrap_or(self.template.len())];
+        let stem = stem.trim_end_matches(['_', '.', '-']);
+        if stem.is_empty() || stem.ends_with('/') {
+            format!("{}manifest.json", stem)
+        } else {
+            format!("{}.manifest.json", stem)
+        }
+    }
+}
+
+// Split currently being written for a key
+// the file is created on the first write, so a split never starts empty
+struct SplitFile {
+    encoder: Option<WBufEncoder>,
+    idx: usize,
+    bytes_written: usize,
+    docs_written: usize,
+    hasher: SeaHasher,
+}
+
+impl SplitFile {
+    fn new(idx: usize) -> Self {
+        Self {
+            encoder: None,
+            idx,
+            bytes_written: 0,
+            docs_written: 0,
+            hasher: SeaHasher::new(),
+        }
+    }
+}
+
+// Write zstd compressed files, starting a new one when the uncompressed size
+// or the number of documents limit is reached.
+// If the name template has `{key}`, documents can be written to a different sequence of
+// splits for each key (e.g. language), each one numbered from 1.
+// Each split is written to a temp file and renamed when it is finished,
+// so files without the temp suffix are always complete.
+// Finished splits are listed in the manifest, see `Manifest`.
 pub struct ZSplit {
-    prefix: String,
-    encoder: WBufEncoder,
+    template: NameTemplate,
+    splits: HashMap<String, SplitFile>,
     size_bytes: usize,
-    bytes_written: usize,
-    num_splits: usize,
+    max_docs: usize,
     compression_level: i32,
     num_threads: u32,
     buffer_size: usize,
+    manifest: Manifest,
 }
 
 impl ZSplit {
@@ -23,66 +148,438 @@ impl ZSplit {
         num_threads: u32,
         buffer_size: usize,
     ) -> std::io::Result<Self> {
+        Self::starting_at(
+            prefix,
+            1,
+            size_bytes,
+            compression_level,
+            num_threads,
+            buffer_size,
+        )
+    }
+
+    // Start writing at split number `idx`, used to resume from a checkpoint
+    // the split is overwritten if it already exists
+    // and the splits already listed in the manifest are kept
+    // `prefix` can also be a name template, see `NameTemplate`
+    // templates with `{key}` can not be resumed, each key has its own split number
+    pub fn starting_at(
+        prefix: &str,
+        idx: usize,
+        size_bytes: usize,
+        compression_level: i32,
+        num_threads: u32,
+        buffer_size: usize,
+    ) -> std::io::Result<Self> {
+        let template = NameTemplate::new(prefix);
+        if idx > 1 && template.has_key() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Output split by key can not be resumed",
+            ));
+        }
+        let manifest_path = template.manifest_path();
+        let mut manifest = Manifest::default();
+        if idx > 1 && Path::new(&manifest_path).exists() {
+            manifest = Manifest::load(&manifest_path)?;
+            manifest
+                .splits
+                .retain(|split| split.key.is_none() && split.index < idx);
+        }
         Ok(Self {
-            prefix: String::from(prefix),
-            encoder: Self::new_file(prefix, 1, compression_level, num_threads, buffer_size)?,
+            template,
+            splits: HashMap::from([(String::new(), SplitFile::new(idx))]),
             size_bytes: size_bytes,
-            bytes_written: 0,
-            num_splits: 1,
+            max_docs: usize::MAX,
             compression_level: compression_level,
             num_threads: num_threads,
             buffer_size: buffer_size,
+            manifest,
         })
     }
 
-    fn new_file(
-        prefix: &str,
-        idx: usize,
-        compression_level: i32,
-        num_threads: u32,
-        buffer_size: usize,
-    ) -> std::io::Result<WBufEncoder> {
-        let file_name = format!("{}.{}.zst", prefix, idx);
-        let file = File::create(file_name)?;
-        let mut encoder = Encoder::new(file, compression_level)?;
-        encoder.multithread(num_threads)?;
-        Ok(BufWriter::with_capacity(buffer_size, encoder.auto_finish()))
-    }
-
-    fn rotate(&mut self) -> std::io::Result<()> {
-        self.num_splits += 1;
-        self.encoder = Self::new_file(
-            &self.prefix,
-            self.num_splits,
-            self.compression_level,
-            self.num_threads,
-            self.buffer_size,
-        )?;
-        self.bytes_written = 0;
+    // Also start a new split after this number of documents
+    pub fn with_max_docs(mut self, max_docs: usize) -> Self {
+        self.max_docs = max_docs;
+        self
+    }
+
+    fn new_file(&self, tmp_name: &str) -> std::io::Result<WBufEncoder> {
+        let file = File::create(tmp_name)?;
+        let mut encoder = Encoder::new(file, self.compression_level)?;
+        encoder.multithread(self.num_threads)?;
+        Ok(BufWriter::with_capacity(self.buffer_size, encoder))
+    }
+
+    // Finish the zstd stream of the current split of a key, rename it to its final name
+    // and add it to the manifest
+    fn finish_file(&mut self, key: &str) -> std::io::Result<()> {
+        let Some(split) = self.splits.get_mut(key) else {
+            return Ok(());
+        };
+        let Some(encoder) = split.encoder.take() else {
+            return Ok(());
+        };
+        let file = encoder.into_inner().map_err(|e| e.into_error())?.finish()?;
+        file.sync_all()?;
+        let compressed_bytes = file.metadata()?.len();
+        drop(file);
+
+        let path = self.template.render(split.idx, key);
+        fs::rename(format!("{}.tmp", path), &path)?;
+        self.manifest.splits.push(SplitInfo {
+            index: split.idx,
+            key: (!key.is_empty()).then(|| key.to_string()),
+            path,
+            num_docs: split.docs_written,
+            uncompressed_bytes: split.bytes_written,
+            compressed_bytes,
+            hash: format!("{:016x}", split.hasher.finish()),
+        });
+        self.manifest.save(&self.template.manifest_path())
+    }
+
+    fn rotate_key(&mut self, key: &str) -> std::io::Result<()> {
+        self.finish_file(key)?;
+        if let Some(split) = self.splits.get_mut(key) {
+            *split = SplitFile::new(split.idx + 1);
+        }
         Ok(())
     }
 
-    pub fn write(&mut self, content: &[u8]) -> std::io::Result<()> {
-        if self.bytes_written > self.size_bytes {
-            self.rotate()?;
+    // Finish the current split, the next write will start a new one
+    pub fn rotate(&mut self) -> std::io::Result<()> {
+        self.rotate_key("")
+    }
+
+    fn is_full_key(&self, key: &str) -> bool {
+        self.splits
+            .get(key)
+            .is_some_and(|s| s.bytes_written > self.size_bytes || s.docs_written >= self.max_docs)
+    }
+
+    // Whether the next write will go to a new split
+    pub fn is_full(&self) -> bool {
+        self.is_full_key("")
+    }
+
+    // Number of the split currently being written
+    pub fn num_splits(&self) -> usize {
+        self.splits.get("").map_or(0, |s| s.idx)
+    }
+
+    // Split where the next content of this key has to be written,
+    // rotating if the limit has been reached and creating the file if needed
+    fn split_for(&mut self, key: &str) -> std::io::Result<&mut SplitFile> {
+        if !key.is_empty() && !self.template.has_key() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Output name template needs '{key}' to split by key",
+            ));
+        }
+        if self.is_full_key(key) {
+            self.rotate_key(key)?;
+        }
+        if !self.splits.contains_key(key) {
+            self.splits.insert(key.to_string(), SplitFile::new(1));
         }
-        self.encoder.write_all(content)?;
-        self.bytes_written += content.len();
+        if self.splits[key].encoder.is_none() {
+            let tmp_name = format!("{}.tmp", self.template.render(self.splits[key].idx, key));
+            let encoder = self.new_file(&tmp_name)?;
+            self.splits.get_mut(key).unwrap().encoder = Some(encoder);
+        }
+        Ok(self.splits.get_mut(key).unwrap())
+    }
+
+    pub fn write(&mut self, content: &[u8]) -> std::io::Result<()> {
+        let split = self.split_for("")?;
+        split.encoder.as_mut().unwrap().write_all(content)?;
+        split.hasher.write(content);
+        split.bytes_written += content.len();
+        split.docs_written += content.iter().filter(|b| **b == b'\n').count();
         Ok(())
     }
 
     pub fn write_line(&mut self, content: &[u8]) -> std::io::Result<()> {
-        if self.bytes_written > self.size_bytes {
-            self.rotate()?;
-        }
-        self.encoder.write_all(content)?;
-        self.encoder.write_all(b"\n")?;
-        self.bytes_written += content.len() + 1;
+        self.write_line_key("", content)
+    }
+
+    // Write a line to the splits of a key, the name template must have `{key}`
+    pub fn write_line_key(&mut self, key: &str, content: &[u8]) -> std::io::Result<()> {
+        let split = self.split_for(key)?;
+        let encoder = split.encoder.as_mut().unwrap();
+        encoder.write_all(content)?;
+        encoder.write_all(b"\n")?;
+        split.hasher.write(content);
+        split.hasher.write(b"\n");
+        split.bytes_written += content.len() + 1;
+        split.docs_written += 1;
         Ok(())
     }
 
 
     pub fn flush(&mut self) -> std::io::Result<()> {
-        self.encoder.flush()
+        for split in self.splits.values_mut() {
+            if let Some(encoder) = &mut split.encoder {
+                encoder.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Finish the last split of every key and mark the manifest as complete
+    // if this is not called, the last splits are left with the temp name
+    pub fn finish(mut self) -> std::io::Result<()> {
+        let mut keys: Vec<String> = self.splits.keys().cloned().collect();
+        keys.sort();
+        for key in keys {
+            self.finish_file(&key)?;
+        }
+        self.manifest.complete = true;
+        self.manifest.save(&self.template.manifest_path())
+    }
+
+    // Stop writing after an error, the unfinished splits are removed
+    // finished splits stay in the manifest, that is not marked as complete
+    pub fn abort(mut self) -> std::io::Result<()> {
+        for (key, split) in self.splits.iter_mut() {
+            if split.encoder.take().is_some() {
+                fs::remove_file(format!("{}.tmp", self.template.render(split.idx, key)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ZSplit {
+    fn drop(&mut self) {
+        let unfinished = self.splits.values().any(|s| s.encoder.is_some());
+        if unfinished && !std::thread::panicking() {
+            warn!(
+                "Output '{}' has not been finished, last splits are left incomplete",
+                self.template.template
+            );
+        }
+    }
+}
+
+// Write output lines either to a buffered stdout or to zstd compressed splits
+pub enum Output {
+    Stdout(BufWriter<io::StdoutLock<'static>>),
+    Split(ZSplit),
+}
+
+impl Output {
+    pub fn stdout(buffer_size: usize) -> Self {
+        Output::Stdout(BufWriter::with_capacity(buffer_size, io::stdout().lock()))
+    }
+
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Stdout(writer) => {
+                writer.write_all(line)?;
+                writer.write_all(b"\n")
+            }
+            Output::Split(writer) => writer.write_line(line),
+        }
+    }
+
+    // Write a line to the splits of a key, stdout does not have splits so the key is ignored
+    pub fn write_line_key(&mut self, key: &str, line: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Stdout(_) => self.write_line(line),
+            Output::Split(writer) => writer.write_line_key(key, line),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(writer) => writer.flush(),
+            Output::Split(writer) => writer.flush(),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Stdout(mut writer) => writer.flush(),
+            Output::Split(writer) => writer.finish(),
+        }
+    }
+
+    // Stop writing after an error, see `ZSplit::abort`
+    pub fn abort(self) -> io::Result<()> {
+        match self {
+            Output::Stdout(mut writer) => writer.flush(),
+            Output::Split(writer) => writer.abort(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // Splits listed in the manifest as (key, index, num_docs, content)
+    fn read_splits(manifest: &Manifest) -> Vec<(Option<String>, usize, usize, String)> {
+        let mut splits = Vec::new();
+        for split in &manifest.splits {
+            let compressed = fs::read(&split.path).unwrap();
+            assert_eq!(compressed.len() as u64, split.compressed_bytes);
+            let content = zstd::decode_all(&compressed[..]).unwrap();
+            assert_eq!(content.len(), split.uncompressed_bytes);
+            let mut hasher = SeaHasher::new();
+            hasher.write(&content);
+            assert_eq!(split.hash, format!("{:016x}", hasher.finish()));
+            let content = String::from_utf8(content).unwrap();
+            splits.push((split.key.clone(), split.index, split.num_docs, content));
+        }
+        splits
+    }
+
+    #[test]
+    fn name_templates() {
+        let template = NameTemplate::new("out/batch");
+        assert_eq!(template.render(3, ""), "out/batch.3.zst");
+        assert_eq!(template.manifest_path(), "out/batch.manifest.json");
+        assert!(!template.has_key());
+
+        let template = NameTemplate::new("batch_{N}.jsonl.zst");
+        assert_eq!(template.render(12, ""), "batch_12.jsonl.zst");
+        assert_eq!(template.manifest_path(), "batch.manifest.json");
+
+        let template = NameTemplate::new("out/{key}_{N:4}.jsonl.zst");
+        assert!(template.has_key());
+        assert_eq!(template.render(7, "CC-2024"), "out/CC-2024_0007.jsonl.zst");
+        assert_eq!(template.render(12345, "a"), "out/a_12345.jsonl.zst");
+        assert_eq!(template.manifest_path(), "out/manifest.json");
+
+        assert_eq!(
+            NameTemplate::new("{N}.zst").manifest_path(),
+            "manifest.json"
+        );
+    }
+
+    #[test]
+    fn manifest_of_splits() {
+        let dir = tempdir().unwrap();
+        let prefix = dir.path().join("batch_{N}.jsonl.zst");
+        let manifest_path = dir.path().join("batch.manifest.json");
+        let mut writer = ZSplit::new(prefix.to_str().unwrap(), usize::MAX, 3, 0, 1024)
+            .unwrap()
+            .with_max_docs(2);
+        for line in ["a", "b", "c", "d", "e"] {
+            writer.write_line(line.as_bytes()).unwrap();
+        }
+        writer.flush().unwrap();
+
+        // only finished splits are listed
+        let manifest = Manifest::load(manifest_path.to_str().unwrap()).unwrap();
+        assert!(!manifest.complete);
+        assert_eq!(manifest.splits.len(), 2);
+        assert!(dir.path().join("batch_3.jsonl.zst.tmp").exists());
+
+        writer.finish().unwrap();
+        let manifest = Manifest::load(manifest_path.to_str().unwrap()).unwrap();
+        assert!(manifest.complete);
+        assert_eq!(
+            read_splits(&manifest),
+            [
+                (None, 1, 2, String::from("a\nb\n")),
+                (None, 2, 2, String::from("c\nd\n")),
+                (None, 3, 1, String::from("e\n")),
+            ]
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn abort_splits() {
+        let dir = tempdir().unwrap();
+        let prefix = dir.path().join("batch_{N}.jsonl.zst");
+        let manifest_path = dir.path().join("batch.manifest.json");
+        let mut writer = ZSplit::new(prefix.to_str().unwrap(), usize::MAX, 3, 0, 1024)
+            .unwrap()
+            .with_max_docs(2);
+        for line in ["a", "b", "c"] {
+            writer.write_line(line.as_bytes()).unwrap();
+        }
+        writer.abort().unwrap();
+
+        // the finished split is kept, the unfinished one is removed
+        let manifest = Manifest::load(manifest_path.to_str().unwrap()).unwrap();
+        assert!(!manifest.complete);
+        assert_eq!(
+            read_splits(&manifest),
+            [(None, 1, 2, String::from("a\nb\n"))]
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn splits_by_key() {
+        let dir = tempdir().unwrap();
+        let prefix = dir.path().join("{key}_{N:2}.zst");
+        let mut writer = ZSplit::new(prefix.to_str().unwrap(), usize::MAX, 3, 0, 1024)
+            .unwrap()
+            .with_max_docs(2);
+        for (key, line) in [("a", "1"), ("b", "2"), ("a", "3"), ("a", "4"), ("b", "5")] {
+            writer.write_line_key(key, line.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let manifest = Manifest::load(dir.path().join("manifest.json").to_str().unwrap()).unwrap();
+        assert!(manifest.complete);
+        let mut splits = read_splits(&manifest);
+        splits.sort();
+        let key = |k: &str| Some(String::from(k));
+        assert_eq!(
+            splits,
+            [
+                (key("a"), 1, 2, String::from("1\n3\n")),
+                (key("a"), 2, 1, String::from("4\n")),
+                (key("b"), 1, 2, String::from("2\n5\n")),
+            ]
+        );
+        assert!(dir.path().join("a_02.zst").exists());
+
+        // a key needs a template with `{key}`
+        let prefix = dir.path().join("batch_{N}.zst");
+        let mut writer = ZSplit::new(prefix.to_str().unwrap(), usize::MAX, 3, 0, 1024).unwrap();
+        assert!(writer.write_line_key("a", b"1").is_err());
+    }
+
+    #[test]
+    fn resume_splits() {
+        let dir = tempdir().unwrap();
+        let prefix = dir.path().join("batch_{N}.zst");
+        let prefix = prefix.to_str().unwrap();
+        let manifest_path = dir.path().join("batch.manifest.json");
+        let mut writer = ZSplit::new(prefix, usize::MAX, 3, 0, 1024)
+            .unwrap()
+            .with_max_docs(1);
+        for line in ["a", "b", "c"] {
+            writer.write_line(line.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        // the splits before the resumed one are kept, the following ones are rewritten
+        let mut writer = ZSplit::starting_at(prefix, 2, usize::MAX, 3, 0, 1024)
+            .unwrap()
+            .with_max_docs(1);
+        writer.write_line(b"x").unwrap();
+        writer.finish().unwrap();
+        let manifest = Manifest::load(manifest_path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            read_splits(&manifest),
+            [
+                (None, 1, 1, String::from("a\n")),
+                (None, 2, 1, String::from("x\n")),
+            ]
+        );
+
+        // each key has its own numbering, a single split number can not resume them
+        let prefix = dir.path().join("{key}_{N}.zst");
+        assert!(ZSplit::starting_at(prefix.to_str().unwrap(), 2, usize::MAX, 3, 0, 1024).is_err());
     }
 }
@@ -0,0 +1,720 @@
+use std::borrow::Cow;
+
+use fnv::FnvHashMap;
+use log::warn;
+use regex::Regex;
+
+// Port of bifixer restorative cleaning as used by monofixer in scripts/annotate.py
+// Each segment (line) is fixed separately, because fixing collapses line breaks.
+// Segments this long (in chars) are left untouched, same as annotate.py does.
+pub const MAX_SEGMENT_CHARS: usize = 50000;
+
+// Replacements applied to every language
+// invisible characters, control characters and unusual spaces
+const CHARS_ANY: &[(char, &str)] = &[
+    ('\u{0000}', ""),
+    ('\u{0001}', ""),
+    ('\u{0002}', ""),
+    ('\u{0003}', ""),
+    ('\u{0004}', ""),
+    ('\u{0005}', ""),
+    ('\u{0006}', ""),
+    ('\u{0007}', ""),
+    ('\u{0008}', ""),
+    ('\u{0009}', " "),
+    ('\u{000B}', " "),
+    ('\u{000C}', " "),
+    ('\u{000D}', " "),
+    ('\u{000E}', ""),
+    ('\u{000F}', ""),
+    ('\u{0010}', ""),
+    ('\u{0011}', ""),
+    ('\u{0012}', ""),
+    ('\u{0013}', ""),
+    ('\u{0014}', ""),
+    ('\u{0015}', ""),
+    ('\u{0016}', ""),
+    ('\u{0017}', ""),
+    ('\u{0018}', ""),
+    ('\u{0019}', ""),
+    ('\u{001A}', ""),
+    ('\u{001B}', ""),
+    ('\u{001C}', ""),
+    ('\u{001D}', ""),
+    ('\u{001E}', ""),
+    ('\u{001F}', ""),
+    ('\u{007F}', ""),
+    ('\u{0085}', " "),
+    ('\u{00A0}', " "),
+    ('\u{00AD}', ""),
+    ('\u{1680}', " "),
+    ('\u{2000}', " "),
+    ('\u{2001}', " "),
+    ('\u{2002}', " "),
+    ('\u{2003}', " "),
+    ('\u{2004}', " "),
+    ('\u{2005}', " "),
+    ('\u{2006}', " "),
+    ('\u{2007}', " "),
+    ('\u{2008}', " "),
+    ('\u{2009}', " "),
+    ('\u{200A}', " "),
+    ('\u{200B}', ""),
+    ('\u{2028}', " "),
+    ('\u{2029}', " "),
+    ('\u{202F}', " "),
+    ('\u{205F}', " "),
+    ('\u{2060}', ""),
+    ('\u{3000}', " "),
+    ('\u{FEFF}', ""),
+    ('\u{FFFD}', ""),
+];
+
+// Romanian uses comma below, not cedilla
+const CHARS_RO: &[(char, &str)] = &[('ş', "ș"), ('Ş', "Ș"), ('ţ', "ț"), ('Ţ', "Ț")];
+
+// Latin homoglyphs found inside Cyrillic words
+// only applied to words that are mostly Cyrillic, see `fix_homoglyphs`
+const HOMOGLYPHS_CYRL: &[(char, char)] = &[
+    ('A', 'А'),
+    ('a', 'а'),
+    ('B', 'В'),
+    ('C', 'С'),
+    ('c', 'с'),
+    ('E', 'Е'),
+    ('e', 'е'),
+    ('H', 'Н'),
+    ('K', 'К'),
+    ('M', 'М'),
+    ('O', 'О'),
+    ('o', 'о'),
+    ('P', 'Р'),
+    ('p', 'р'),
+    ('T', 'Т'),
+    ('X', 'Х'),
+    ('x', 'х'),
+    ('y', 'у'),
+];
+
+const LANGS_CYRL: &[&str] = &[
+    "be", "bg", "kk", "ky", "mk", "mn", "ru", "sr", "tg", "tt", "uk",
+];
+
+// Latin homoglyphs found inside Greek words
+const HOMOGLYPHS_GREK: &[(char, char)] = &[
+    ('A', 'Α'),
+    ('B', 'Β'),
+    ('E', 'Ε'),
+    ('H', 'Η'),
+    ('I', 'Ι'),
+    ('K', 'Κ'),
+    ('M', 'Μ'),
+    ('N', 'Ν'),
+    ('O', 'Ο'),
+    ('o', 'ο'),
+    ('P', 'Ρ'),
+    ('T', 'Τ'),
+    ('X', 'Χ'),
+    ('Y', 'Υ'),
+    ('Z', 'Ζ'),
+];
+
+// HTML elements removed by `remove_html_tags`
+// anything else between angle brackets is regular text (e.g. "x<y>z")
+const HTML_TAGS: &[&str] = &[
+    "a",
+    "abbr",
+    "address",
+    "area",
+    "article",
+    "aside",
+    "audio",
+    "b",
+    "base",
+    "bdi",
+    "bdo",
+    "big",
+    "blockquote",
+    "body",
+    "br",
+    "button",
+    "canvas",
+    "caption",
+    "center",
+    "cite",
+    "code",
+    "col",
+    "colgroup",
+    "data",
+    "dd",
+    "del",
+    "details",
+    "dfn",
+    "dialog",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "embed",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "font",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hr",
+    "html",
+    "i",
+    "iframe",
+    "img",
+    "input",
+    "ins",
+    "kbd",
+    "label",
+    "legend",
+    "li",
+    "link",
+    "main",
+    "map",
+    "mark",
+    "meta",
+    "nav",
+    "noscript",
+    "object",
+    "ol",
+    "optgroup",
+    "option",
+    "output",
+    "p",
+    "param",
+    "picture",
+    "pre",
+    "q",
+    "s",
+    "samp",
+    "script",
+    "section",
+    "select",
+    "small",
+    "source",
+    "span",
+    "strike",
+    "strong",
+    "style",
+    "sub",
+    "summary",
+    "sup",
+    "svg",
+    "table",
+    "tbody",
+    "td",
+    "template",
+    "textarea",
+    "tfoot",
+    "th",
+    "thead",
+    "time",
+    "title",
+    "tr",
+    "track",
+    "tt",
+    "u",
+    "ul",
+    "var",
+    "video",
+    "wbr",
+];
+
+// Named HTML entities that are found in crawled text
+// numeric entities are decoded in `unescape_html`
+const HTML_ENTITIES: &[(&str, &str)] = &[
+    ("amp", "&"),
+    ("lt", "<"),
+    ("gt", ">"),
+    ("quot", "\""),
+    ("apos", "'"),
+    ("nbsp", " "),
+    ("shy", ""),
+    ("copy", "©"),
+    ("reg", "®"),
+    ("trade", "™"),
+    ("deg", "°"),
+    ("euro", "€"),
+    ("pound", "£"),
+    ("yen", "¥"),
+    ("cent", "¢"),
+    ("sect", "§"),
+    ("para", "¶"),
+    ("middot", "·"),
+    ("bull", "•"),
+    ("hellip", "…"),
+    ("ndash", "–"),
+    ("mdash", "—"),
+    ("lsquo", "‘"),
+    ("rsquo", "’"),
+    ("sbquo", "‚"),
+    ("ldquo", "“"),
+    ("rdquo", "”"),
+    ("bdquo", "„"),
+    ("laquo", "«"),
+    ("raquo", "»"),
+    ("times", "×"),
+    ("divide", "÷"),
+    ("iexcl", "¡"),
+    ("iquest", "¿"),
+    ("Agrave", "À"),
+    ("Aacute", "Á"),
+    ("Acirc", "Â"),
+    ("Auml", "Ä"),
+    ("Ccedil", "Ç"),
+    ("Egrave", "È"),
+    ("Eacute", "É"),
+    ("Ecirc", "Ê"),
+    ("Iacute", "Í"),
+    ("Ntilde", "Ñ"),
+    ("Oacute", "Ó"),
+    ("Ouml", "Ö"),
+    ("Uacute", "Ú"),
+    ("Uuml", "Ü"),
+    ("szlig", "ß"),
+    ("agrave", "à"),
+    ("aacute", "á"),
+    ("acirc", "â"),
+    ("auml", "ä"),
+    ("aring", "å"),
+    ("ccedil", "ç"),
+    ("egrave", "è"),
+    ("eacute", "é"),
+    ("ecirc", "ê"),
+    ("euml", "ë"),
+    ("igrave", "ì"),
+    ("iacute", "í"),
+    ("icirc", "î"),
+    ("iuml", "ï"),
+    ("ntilde", "ñ"),
+    ("ograve", "ò"),
+    ("oacute", "ó"),
+    ("ocirc", "ô"),
+    ("ouml", "ö"),
+    ("oslash", "ø"),
+    ("ugrave", "ù"),
+    ("uacute", "ú"),
+    ("ucirc", "û"),
+    ("uuml", "ü"),
+];
+
+// Obtain the language code used by monofixer from a pipeline language code
+// Hans/Hant scripts are 'zh' as in annotate.py, but only the languages with specific
+// replacements in this port are mapped, the rest fall back to 'any'
+pub fn monofixer_lang(langcode: &str) -> &str {
+    let mut parts = langcode.split('_');
+    let lang = parts.next().unwrap_or("");
+    if let Some("Hans" | "Hant") = parts.next() {
+        return "zh";
+    }
+    match lang {
+        "ron" | "ro" => "ro",
+        "hbs" => "hbs",
+        "bel" | "be" => "be",
+        "bul" | "bg" => "bg",
+        "kaz" | "kk" => "kk",
+        "kir" | "ky" => "ky",
+        "mkd" | "mk" => "mk",
+        "khk" | "mon" | "mn" => "mn",
+        "rus" | "ru" => "ru",
+        "srp" | "sr" => "sr",
+        "tgk" | "tg" => "tg",
+        "tat" | "tt" => "tt",
+        "ukr" | "uk" => "uk",
+        "ell" | "el" => "el",
+        _ => "any",
+    }
+}
+
+// homoglyph replacements and the script of the words where they are applied
+type Homoglyphs = (FnvHashMap<char, char>, fn(char) -> bool);
+
+pub struct TextFixer {
+    replacements: FnvHashMap<char, &'static str>,
+    entities: FnvHashMap<&'static str, &'static str>,
+    homoglyphs: Option<Homoglyphs>,
+    entity_re: Regex,
+    tag_re: Regex,
+}
+
+impl TextFixer {
+    pub fn new(langcode: &str) -> Self {
+        let lang = monofixer_lang(langcode);
+        if lang != "ro" && lang != "el" && !LANGS_CYRL.contains(&lang) {
+            warn!(
+                "No specific text fixing replacements for '{}', only the generic ones are applied",
+                langcode
+            );
+        }
+        let mut replacements: FnvHashMap<char, &'static str> = CHARS_ANY.iter().copied().collect();
+        if lang == "ro" {
+            replacements.extend(CHARS_RO.iter().copied());
+        }
+        let homoglyphs: Option<Homoglyphs> = if LANGS_CYRL.contains(&lang) {
+            Some((HOMOGLYPHS_CYRL.iter().copied().collect(), is_cyrillic))
+        } else if lang == "el" {
+            Some((HOMOGLYPHS_GREK.iter().copied().collect(), is_greek))
+        } else {
+            None
+        };
+
+        Self {
+            replacements,
+            entities: HTML_ENTITIES.iter().copied().collect(),
+            homoglyphs,
+            entity_re: Regex::new(r"&(#[0-9]{1,7}|#[xX][0-9a-fA-F]{1,6}|[a-zA-Z]{2,8});")
+                .expect("Error creating regex"),
+            tag_re: Regex::new(r"<!--.*?-->|</?([a-zA-Z][a-zA-Z0-9]*)(?:\s[^<>]*)?/?>")
+                .expect("Error creating regex"),
+        }
+    }
+
+    // Fix a full document text, segment by segment
+    pub fn fix(&self, text: &str) -> String {
+        let mut fixed = String::with_capacity(text.len());
+        for (i, segment) in text.split('\n').enumerate() {
+            if i > 0 {
+                fixed.push('\n');
+            }
+            // the guard is on chars, but there can't be more chars than bytes
+            if segment.len() >= MAX_SEGMENT_CHARS && segment.chars().count() >= MAX_SEGMENT_CHARS {
+                fixed.push_str(segment);
+                continue;
+            }
+            fixed.push_str(&self.fix_segment(segment));
+        }
+        fixed
+    }
+
+    // Equivalent to restorative_cleaning.fix followed by remove_html_tags
+    pub fn fix_segment(&self, segment: &str) -> String {
+        let text = self.unescape_html(segment);
+        let text = fix_mojibake(&text);
+        let text = self.replace_chars(&text);
+        let text = match &self.homoglyphs {
+            Some((table, in_script)) => Cow::Owned(fix_homoglyphs(&text, table, *in_script)),
+            None => Cow::Borrowed(text.as_str()),
+        };
+        let text = self.remove_html_tags(&text);
+        collapse_spaces(&text)
+    }
+
+    // Remove HTML comments and tags of known HTML elements
+    pub fn remove_html_tags<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if !text.contains('<') {
+            return Cow::Borrowed(text);
+        }
+        self.tag_re
+            .replace_all(text, |caps: &regex::Captures| match caps.get(1) {
+                Some(name) if !HTML_TAGS.contains(&name.as_str().to_ascii_lowercase().as_str()) => {
+                    caps[0].to_string()
+                }
+                _ => String::new(),
+            })
+    }
+
+    // Decode named and numeric HTML entities
+    // unknown entities are kept as is
+    pub fn unescape_html<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if !text.contains('&') {
+            return Cow::Borrowed(text);
+        }
+        self.entity_re.replace_all(text, |caps: &regex::Captures| {
+            let entity = &caps[1];
+            let decoded = if let Some(num) = entity.strip_prefix('#') {
+                let code = match num.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => num.parse::<u32>().ok(),
+                };
+                code.and_then(char::from_u32).map(String::from)
+            } else {
+                self.entities.get(entity).map(|s| s.to_string())
+            };
+            decoded.unwrap_or_else(|| caps[0].to_string())
+        })
+    }
+
+    fn replace_chars(&self, text: &str) -> String {
+        let mut fixed = String::with_capacity(text.len());
+        for c in text.chars() {
+            match self.replacements.get(&c) {
+                Some(rep) => fixed.push_str(rep),
+                None => fixed.push(c),
+            }
+        }
+        fixed
+    }
+}
+
+// Byte that a char had before UTF-8 text was wrongly decoded as Windows-1252/Latin-1
+fn cp1252_byte(c: char) -> Option<u8> {
+    let code = c as u32;
+    if (0xA0..=0xFF).contains(&code) {
+        return Some(code as u8);
+    }
+    let byte = match c {
+        '€' => 0x80,
+        '‚' => 0x82,
+        'ƒ' => 0x83,
+        '„' => 0x84,
+        '…' => 0x85,
+        '†' => 0x86,
+        '‡' => 0x87,
+        'ˆ' => 0x88,
+        '‰' => 0x89,
+        'Š' => 0x8A,
+        '‹' => 0x8B,
+        'Œ' => 0x8C,
+        'Ž' => 0x8E,
+        '‘' => 0x91,
+        '’' => 0x92,
+        '“' => 0x93,
+        '”' => 0x94,
+        '•' => 0x95,
+        '–' => 0x96,
+        '—' => 0x97,
+        '˜' => 0x98,
+        '™' => 0x99,
+        'š' => 0x9A,
+        '›' => 0x9B,
+        'œ' => 0x9C,
+        'ž' => 0x9E,
+        'Ÿ' => 0x9F,
+        // undefined positions in cp1252 are decoded as C1 controls
+        '\u{81}' | '\u{8D}' | '\u{8F}' | '\u{90}' | '\u{9D}' => code as u8,
+        _ => return None,
+    };
+    Some(byte)
+}
+
+// Repair UTF-8 sequences that were decoded as Windows-1252 (e.g. 'Ã©' -> 'é')
+// each sequence is only replaced if the re-encoded bytes are valid UTF-8,
+// so legitimate accented characters are left untouched
+pub fn fix_mojibake(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut fixed = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let lead = cp1252_byte(chars[i]);
+        let seq_len = match lead {
+            Some(0xC2..=0xDF) => 2,
+            Some(0xE0..=0xEF) => 3,
+            Some(0xF0..=0xF4) => 4,
+            _ => 0,
+        };
+        if seq_len > 0 && i + seq_len <= chars.len() {
+            let mut bytes = Vec::with_capacity(seq_len);
+            bytes.push(lead.unwrap());
+            for c in &chars[i + 1..i + seq_len] {
+                match cp1252_byte(*c) {
+                    Some(b @ 0x80..=0xBF) => bytes.push(b),
+                    _ => break,
+                }
+            }
+            if bytes.len() == seq_len {
+                if let Ok(decoded) = std::str::from_utf8(&bytes) {
+                    fixed.push_str(decoded);
+                    i += seq_len;
+                    continue;
+                }
+            }
+        }
+        fixed.push(chars[i]);
+        i += 1;
+    }
+    fixed
+}
+
+fn is_cyrillic(c: char) -> bool {
+    matches!(c, '\u{0400}'..='\u{052F}')
+}
+
+fn is_greek(c: char) -> bool {
+    matches!(c, '\u{0370}'..='\u{03FF}' | '\u{1F00}'..='\u{1FFF}')
+}
+
+// Replace Latin homoglyphs in words that are mostly written in another script
+fn fix_homoglyphs(
+    text: &str,
+    table: &FnvHashMap<char, char>,
+    in_script: fn(char) -> bool,
+) -> String {
+    let mut fixed = String::with_capacity(text.len());
+    for (i, word) in text.split(' ').enumerate() {
+        if i > 0 {
+            fixed.push(' ');
+        }
+        let num_script = word.chars().filter(|c| in_script(*c)).count();
+        let num_latin = word.chars().filter(|c| c.is_ascii_alphabetic()).count();
+        if num_script == 0 || num_latin == 0 || num_latin >= num_script {
+            fixed.push_str(word);
+            continue;
+        }
+        for c in word.chars() {
+            fixed.push(*table.get(&c).unwrap_or(&c));
+        }
+    }
+    fixed
+}
+
+// Collapse consecutive spaces and strip them from both ends
+fn collapse_spaces(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    for word in text.split(' ').filter(|w| !w.is_empty()) {
+        if !collapsed.is_empty() {
+            collapsed.push(' ');
+        }
+        collapsed.push_str(word);
+    }
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mojibake() {
+        let cases = [
+            ("cafÃ©", "café"),
+            ("Ã¼ber straÃŸe", "über straße"),
+            ("â€œquotedâ€\u{9D}", "“quoted”"),
+            ("â‚¬ 10", "€ 10"),
+            ("ðŸ˜€", "😀"),
+            // legitimate text is left untouched
+            ("café Ã", "café Ã"),
+            ("naïve Â", "naïve Â"),
+            ("plain ascii", "plain ascii"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(fix_mojibake(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn entities() {
+        let fixer = TextFixer::new("eng_Latn");
+        let cases = [
+            ("a &amp; b", "a & b"),
+            ("&lt;p&gt;", "<p>"),
+            ("&quot;x&quot; &apos;y&apos;", "\"x\" 'y'"),
+            ("caf&eacute; &euro;", "café €"),
+            ("&#233;&#xE9;&#XE9;", "ééé"),
+            ("&#128512;", "😀"),
+            // unknown and invalid entities are kept
+            ("&foo; &#xD800; & amp;", "&foo; &#xD800; & amp;"),
+            ("no entities", "no entities"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(fixer.unescape_html(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn html_tags() {
+        let fixer = TextFixer::new("eng_Latn");
+        let cases = [
+            ("<p>text</p>", "text"),
+            ("a<br/>b<BR>c", "abc"),
+            ("<a href=\"x\">link</a>", "link"),
+            ("x<!-- comment -->y", "xy"),
+            // not HTML elements
+            ("x<y>z", "x<y>z"),
+            ("i<j and k>l", "i<j and k>l"),
+            ("<foo bar>", "<foo bar>"),
+            ("1 < 2 > 0", "1 < 2 > 0"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(fixer.remove_html_tags(text), expected, "{:?}", text);
+        }
+        // escaped tags are decoded and removed, escaped text is kept
+        assert_eq!(fixer.fix_segment("&lt;b&gt;bold&lt;/b&gt;"), "bold");
+        assert_eq!(fixer.fix_segment("x &lt;y&gt; z"), "x <y> z");
+    }
+
+    #[test]
+    fn homoglyphs() {
+        let cases = [
+            ("rus_Cyrl", "Мoсква", "Москва"),
+            ("ukr_Cyrl", "Kиїв", "Київ"),
+            // mostly Latin words or words without Cyrillic are kept
+            ("rus_Cyrl", "Moscow", "Moscow"),
+            ("rus_Cyrl", "Moсcow", "Moсcow"),
+            ("ell_Grek", "ΑΘΗΝA", "ΑΘΗΝΑ"),
+            ("ell_Grek", "Athens", "Athens"),
+            // only applied to the languages of the script
+            ("eng_Latn", "Мocквa", "Мocквa"),
+            ("ell_Grek", "Мocквa", "Мocквa"),
+        ];
+        for (lang, text, expected) in cases {
+            assert_eq!(
+                TextFixer::new(lang).fix(text),
+                expected,
+                "{} {:?}",
+                lang,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn replacements() {
+        let fixer = TextFixer::new("eng_Latn");
+        assert_eq!(fixer.fix("a\u{00A0}\u{200B}b\tc  d "), "a b c d");
+        assert_eq!(fixer.fix("line 1 \n\n  line 2"), "line 1\n\nline 2");
+        assert_eq!(fixer.fix("şţ"), "şţ");
+        assert_eq!(TextFixer::new("ron_Latn").fix("Şi ţară"), "Și țară");
+    }
+
+    #[test]
+    fn languages() {
+        let cases = [
+            ("ron_Latn", "ro"),
+            ("rus_Cyrl", "ru"),
+            ("ell_Grek", "el"),
+            ("hbs_Latn", "hbs"),
+            ("cmn_Hans", "zh"),
+            ("zho_Hant", "zh"),
+            ("eng_Latn", "any"),
+            ("xyz", "any"),
+        ];
+        for (langcode, expected) in cases {
+            assert_eq!(monofixer_lang(langcode), expected, "{}", langcode);
+        }
+    }
+
+    #[test]
+    fn long_segments() {
+        let fixer = TextFixer::new("eng_Latn");
+        let long = "a&amp;b  ".repeat(MAX_SEGMENT_CHARS / 9 + 1);
+        let short = "a&amp;b  ".repeat((MAX_SEGMENT_CHARS - 1) / 9);
+        assert!(long.chars().count() >= MAX_SEGMENT_CHARS);
+        assert!(short.chars().count() < MAX_SEGMENT_CHARS);
+
+        let text = format!("{}\n{}\nx&amp;y", long, short);
+        let fixed = fixer.fix(&text);
+        let segments: Vec<&str> = fixed.split('\n').collect();
+        assert_eq!(segments[0], long);
+        assert_eq!(segments[1], fixer.fix_segment(&short));
+        assert!(!segments[1].contains("&amp;"));
+        assert_eq!(segments[2], "x&y");
+
+        // the guard counts chars, not bytes
+        let multibyte = "é".repeat(MAX_SEGMENT_CHARS - 10) + "&amp;";
+        assert!(multibyte.len() >= MAX_SEGMENT_CHARS);
+        assert!(!fixer.fix(&multibyte).contains("&amp;"));
+    }
+}
@@ -0,0 +1,369 @@
+// URL normalization, so document urls and robots.txt patterns can be compared
+// Urls are identified by their origin (scheme, host and port) and path.
+// The host is lowercased and converted to punycode, default ports, user info and fragment
+// are removed and the path is percent-encoded as described in RFC 9309 section 2.2.2.
+// Normalization never fails, urls that can not be parsed are kept as close as possible.
+
+// A normalized url
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    // lowercased, 'http' if the url had no scheme
+    pub scheme: String,
+    pub host: String,
+    // only if it is not the default port of the scheme
+    pub port: Option<u16>,
+    // path and query, always starts with '/'
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Self {
+        let url = url.trim();
+        let url = url.split('#').next().unwrap_or(url);
+
+        let (scheme, rest) = match url.split_once("://") {
+            Some((scheme, rest))
+                if !scheme.is_empty()
+                    && scheme
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) =>
+            {
+                (scheme.to_ascii_lowercase(), rest)
+            }
+            _ => (String::from("http"), url),
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(pos) => rest.split_at(pos),
+            None => (rest, ""),
+        };
+        // remove user info
+        let authority = authority.rsplit('@').next().unwrap_or(authority);
+
+        // the port is what is after the last ':' if it is a number,
+        // so ipv6 addresses like '[::1]' are not taken as a port
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if port.bytes().all(|c| c.is_ascii_digit()) => {
+                (host, port.parse::<u16>().ok())
+            }
+            _ => (authority, None),
+        };
+        let port = port.filter(|port| Some(*port) != default_port(&scheme));
+
+        let path = if path.starts_with('/') {
+            normalize_path(path)
+        } else {
+            normalize_path(&format!("/{path}"))
+        };
+
+        Self {
+            host: normalize_host(host),
+            scheme,
+            port,
+            path,
+        }
+    }
+
+    // Scheme, host and port, the scope of a robots.txt
+    pub fn origin(&self) -> String {
+        origin(&self.scheme, &self.host, self.port)
+    }
+
+    // Origin and path, the key of the url in the indexes
+    pub fn key(&self) -> String {
+        format!("{}{}", self.origin(), self.path)
+    }
+}
+
+// Origin of a url with the given parts, the port only if it is not the default
+pub fn origin(scheme: &str, host: &str, port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("{scheme}://{host}:{port}"),
+        None => format!("{scheme}://{host}"),
+    }
+}
+
+// Split a url key in origin and path
+// also works for an origin followed by a robots.txt pattern, that may start with '*'
+pub fn split_key(key: &str) -> (&str, &str) {
+    let start = key.find("://").map_or(0, |pos| pos + 3);
+    match key[start..].find(['/', '*']) {
+        Some(pos) => key.split_at(start + pos),
+        None => (key, ""),
+    }
+}
+
+// Normalized key of a url, see Url::key
+pub fn url_key(url: &str) -> String {
+    Url::parse(url).key()
+}
+
+// Url key without the scheme and 'www.', the format of the lists of disallowed urls
+// written by robots-filter, so they can still be searched removing the prefix
+// of the urls with '^(https?://)?(www\.)?' (e.g. disallow-filter or annotate.py)
+pub fn disallowed_key(key: &str) -> &str {
+    let key = key.find("://").map_or(key, |pos| &key[pos + 3..]);
+    key.strip_prefix("www.").unwrap_or(key)
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+// Lowercase the host, remove the trailing dot and convert internationalized labels to punycode
+pub fn normalize_host(host: &str) -> String {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_ascii() {
+        return host.to_ascii_lowercase();
+    }
+    host.split('.')
+        .map(|label| {
+            let label = label.to_lowercase();
+            if label.is_ascii() {
+                return label;
+            }
+            match punycode(&label) {
+                Some(encoded) => format!("xn--{encoded}"),
+                None => label,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// Percent-encode a path or a path pattern as in RFC 9309:
+// non-ASCII, whitespace and control characters are encoded,
+// encoded unreserved characters are decoded and the rest of encoded characters are uppercased.
+// '*' and '$' are not touched, so robots.txt patterns can be normalized too.
+pub fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut normalized = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'%' {
+            let decoded = bytes
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(decoded) = decoded {
+                if is_unreserved(decoded) {
+                    normalized.push(decoded as char);
+                } else {
+                    push_encoded(&mut normalized, decoded);
+                }
+                i += 3;
+                continue;
+            }
+        }
+        if c > b' ' && c < 0x7f {
+            normalized.push(c as char);
+        } else {
+            push_encoded(&mut normalized, c);
+        }
+        i += 1;
+    }
+    normalized
+}
+
+fn is_unreserved(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, b'-' | b'.' | b'_' | b'~')
+}
+
+fn push_encoded(buf: &mut String, c: u8) {
+    buf.push_str(&format!("%{c:02X}"));
+}
+
+// Punycode encoding of a label (RFC 3492), without the 'xn--' prefix
+// None if it overflows
+fn punycode(input: &str) -> Option<String> {
+    const BASE: u32 = 36;
+    const TMIN: u32 = 1;
+    const TMAX: u32 = 26;
+
+    let adapt = |delta: u32, num_points: u32, first_time: bool| {
+        let mut delta = if first_time { delta / 700 } else { delta / 2 };
+        delta += delta / num_points;
+        let mut k = 0;
+        while delta > ((BASE - TMIN) * TMAX) / 2 {
+            delta /= BASE - TMIN;
+            k += BASE;
+        }
+        k + (BASE - TMIN + 1) * delta / (delta + 38)
+    };
+    let digit = |d: u32| match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    };
+
+    let chars: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    let mut output: String = input.chars().filter(char::is_ascii).collect();
+    let num_basic = output.len() as u32;
+    if num_basic > 0 {
+        output.push('-');
+    }
+
+    let (mut n, mut delta, mut bias) = (128_u32, 0_u32, 72_u32);
+    let mut handled = num_basic;
+    while (handled as usize) < chars.len() {
+        let m = *chars.iter().filter(|&&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &c in &chars {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = if k <= bias {
+                        TMIN
+                    } else if k >= bias + TMAX {
+                        TMAX
+                    } else {
+                        k - bias
+                    };
+                    if q < t {
+                        break;
+                    }
+                    output.push(digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(digit(q));
+                bias = adapt(delta, handled + 1, handled == num_basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta += 1;
+        n += 1;
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_keys() {
+        let cases = [
+            ("https://example.com/a", "https://example.com/a"),
+            (
+                "HTTP://WWW.Example.COM:80/a#top",
+                "http://www.example.com/a",
+            ),
+            ("https://example.com:443", "https://example.com/"),
+            (
+                "https://example.com:8443?q=1",
+                "https://example.com:8443/?q=1",
+            ),
+            ("http://user:pw@example.com/a", "http://example.com/a"),
+            ("example.com/a", "http://example.com/a"),
+            ("  ftp://example.com:21/a ", "ftp://example.com:21/a"),
+            ("http://[::1]:8080/a", "http://[::1]:8080/a"),
+            ("http://[::1]/a", "http://[::1]/a"),
+            ("http://example.com./a", "http://example.com/a"),
+            ("http://Bücher.de/ü", "http://xn--bcher-kva.de/%C3%BC"),
+            ("münchen.de", "http://xn--mnchen-3ya.de/"),
+        ];
+        for (url, key) in cases {
+            assert_eq!(url_key(url), key, "{url}");
+        }
+
+        let url = Url::parse("https://www.example.com:8443/a/b?c");
+        assert_eq!(url.scheme, "https");
+        assert_eq!(url.host, "www.example.com");
+        assert_eq!(url.port, Some(8443));
+        assert_eq!(url.path, "/a/b?c");
+        assert_eq!(url.origin(), "https://www.example.com:8443");
+    }
+
+    #[test]
+    fn normalized_paths() {
+        let cases = [
+            ("/a/b?c=d&e", "/a/b?c=d&e"),
+            // unreserved characters are decoded, the rest uppercased
+            ("/%7euser/%41%2d%5F", "/~user/A-_"),
+            ("/a%2fb%3f", "/a%2Fb%3F"),
+            ("/a b\t", "/a%20b%09"),
+            ("/ü€", "/%C3%BC%E2%82%AC"),
+            ("/%C3%BC", "/%C3%BC"),
+            // invalid escapes are kept
+            ("/%zz%4", "/%zz%4"),
+            // robots.txt patterns
+            ("/*.html$", "/*.html$"),
+            ("/a%2a*", "/a%2A*"),
+        ];
+        for (path, normalized) in cases {
+            assert_eq!(normalize_path(path), normalized, "{path}");
+        }
+    }
+
+    #[test]
+    fn idn_hosts() {
+        // RFC 3492 section 7.1 samples
+        let cases = [
+            ("ليهمابتكلموشعربي؟", "egbpdaj6bu4bxfgehfvwxn"),
+            ("他们为什么不说中文", "ihqwcrb4cv8a8dqg056pqjye"),
+            (
+                "почемужеонинеговорятпорусски",
+                "b1abfaaepdrnnbgefbadotcwatmq2g4l",
+            ),
+            ("3年B組金八先生", "3B-ww4c5e180e575a65lsy2b"),
+            ("bücher", "bcher-kva"),
+        ];
+        for (label, encoded) in cases {
+            assert_eq!(punycode(label).as_deref(), Some(encoded), "{label}");
+        }
+
+        let cases = [
+            ("münchen.de", "xn--mnchen-3ya.de"),
+            ("MÜNCHEN.DE.", "xn--mnchen-3ya.de"),
+            ("www.例え.jp", "www.xn--r8jz45g.jp"),
+            ("Example.COM", "example.com"),
+        ];
+        for (host, normalized) in cases {
+            assert_eq!(normalize_host(host), normalized, "{host}");
+        }
+    }
+
+    #[test]
+    fn split_keys() {
+        let cases = [
+            ("https://a.com/x/y", ("https://a.com", "/x/y")),
+            ("https://a.com:8080/", ("https://a.com:8080", "/")),
+            ("https://a.com*.php", ("https://a.com", "*.php")),
+            ("https://a.com", ("https://a.com", "")),
+            ("a.com/x", ("a.com", "/x")),
+        ];
+        for (key, parts) in cases {
+            assert_eq!(split_key(key), parts, "{key}");
+        }
+    }
+
+    #[test]
+    fn disallowed_keys() {
+        let cases = [
+            ("https://www.a.com/x", "a.com/x"),
+            ("http://a.com:8080/x", "a.com:8080/x"),
+            ("https://www2.a.com/", "www2.a.com/"),
+            ("https://a.com/www.x", "a.com/www.x"),
+            ("a.com/x", "a.com/x"),
+        ];
+        for (key, expected) in cases {
+            assert_eq!(disallowed_key(key), expected, "{key}");
+        }
+        // same as removing the prefix with '^(https?://)?(www\.)?' from the urls
+        assert_eq!(
+            disallowed_key(&url_key("HTTPS://WWW.A.com:443/x#f")),
+            "a.com/x"
+        );
+    }
+}
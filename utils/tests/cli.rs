@@ -1,7 +1,11 @@
 use assert_cmd::Command;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use fst::Set;
+use monotextor_utils::url::{disallowed_key, url_key};
 use std::fs;
 use std::io::Write;
-use tempfile::NamedTempFile;
+use tempfile::{tempdir, NamedTempFile};
 use zstd::bulk::compress;
 
 // Test that checks index and dedup of a sample of documents
@@ -51,3 +55,1024 @@ fn tsv2jsonl() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+// Build an FST index of urls in a temp file
+fn url_index(urls: &[&str]) -> Result<NamedTempFile, Box<dyn std::error::Error>> {
+    let mut urls = urls.to_vec();
+    urls.sort();
+    let mut temp = NamedTempFile::new()?;
+    temp.write_all(Set::from_iter(urls)?.as_fst().as_bytes())?;
+    Ok(temp)
+}
+
+// Write robots.txt in warc2text JSONL format, a (domain, body) for each one
+// the domain is fetched with https, unless it has a scheme
+fn robots_jsonl(robots: &[(&str, &str)]) -> Result<NamedTempFile, Box<dyn std::error::Error>> {
+    let mut robots_file = NamedTempFile::new()?;
+    for (domain, body) in robots {
+        let origin = if domain.contains("://") {
+            domain.to_string()
+        } else {
+            format!("https://{domain}")
+        };
+        writeln!(
+            robots_file,
+            "{}",
+            serde_json::json!({"u": format!("{origin}/robots.txt"), "p": body})
+        )?;
+    }
+    Ok(robots_file)
+}
+
+// Run robots-filter over a set of urls and robots.txt, return the sorted disallowed urls
+fn robots_filter(
+    urls: &[&str],
+    robots: &[(&str, &str)],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    robots_filter_args(urls, robots, &[])
+}
+
+fn robots_filter_args(
+    urls: &[&str],
+    robots: &[(&str, &str)],
+    args: &[&str],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let index = url_index(urls)?;
+    let robots_file = robots_jsonl(robots)?;
+
+    let output = Command::cargo_bin("robots-filter")?
+        .arg("--format")
+        .arg("robots")
+        .args(args)
+        .arg(index.path())
+        .arg(robots_file.path())
+        .output()?;
+    let mut disallowed: Vec<String> = std::str::from_utf8(&output.stdout)?
+        .lines()
+        .map(String::from)
+        .collect();
+    disallowed.sort();
+    Ok(disallowed)
+}
+
+// Compile the robots.txt and annotate the urls with robotstxt-annotate, return allowed of each url
+fn robots_annotate(
+    urls: &[&str],
+    robots: &[(&str, &str)],
+    args: &[&str],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let robots_file = robots_jsonl(robots)?;
+    let docs: Vec<_> = urls
+        .iter()
+        .map(|url| serde_json::json!({ "u": url }))
+        .collect();
+    annotate_docs(&robots_file, &docs, args)
+}
+
+// Compile a robots.txt JSONL file and annotate the metadata documents with robotstxt-annotate
+fn annotate_docs(
+    robots_file: &NamedTempFile,
+    docs: &[serde_json::Value],
+    args: &[&str],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let index = NamedTempFile::new()?;
+    Command::cargo_bin("robots-compile")?
+        .arg(index.path())
+        .arg(robots_file.path())
+        .assert()
+        .success();
+
+    let mut metadata = NamedTempFile::new()?;
+    for doc in docs {
+        writeln!(metadata, "{doc}")?;
+    }
+    let annotations = NamedTempFile::new()?;
+    Command::cargo_bin("robotstxt-annotate")?
+        .arg("--robots-index")
+        .arg(index.path())
+        .args(args)
+        .arg(metadata.path())
+        .arg(annotations.path())
+        .assert()
+        .success();
+
+    let annotations = String::from_utf8(zstd::decode_all(fs::File::open(annotations.path())?)?)?;
+    Ok(annotations.lines().map(String::from).collect())
+}
+
+// Expected annotations of the urls given the disallowed urls by robots-filter
+fn expected_annotations(urls: &[&str], disallowed: &[String]) -> Vec<String> {
+    urls.iter()
+        .map(|url| {
+            let key = disallowed_key(&url_key(url)).to_string();
+            format!("{{\"allowed\": {}}}", !disallowed.contains(&key))
+        })
+        .collect()
+}
+
+// Test that robots.txt files are parsed with the rules of the right user-agents
+#[test]
+fn robots_filter_robotstxt() -> Result<(), Box<dyn std::error::Error>> {
+    let urls = [
+        "https://www.example.com/google",
+        "https://www.example.com/private",
+        "https://www.example.com/private/x",
+        "https://www.example.com/public",
+        "https://www.example.com/tmp/1",
+        "https://other.org/x",
+        "https://empty.net/x",
+    ];
+    let robots = [
+        (
+            "www.example.com",
+            "\u{feff}User-agent: Googlebot\nDisallow: /google\n\n\
+             USER-AGENT: CCBot/2.0 # us\nuser-agent: other\ndisallow: /private # comment\n\n\
+             User-agent: *\nDisallow: /tmp\r\nSitemap: https://example.com/s.xml\n",
+        ),
+        ("other.org", "User-agent: googlebot\nDisallow: /\n"),
+        // an empty disallow ends the group, the next one is not for us
+        (
+            "empty.net",
+            "User-agent: *\nDisallow:\n\nUser-agent: BadBot\nDisallow: /\n",
+        ),
+    ];
+    assert_eq!(
+        robots_filter(&urls, &robots)?,
+        [
+            "example.com/private",
+            "example.com/private/x",
+            "example.com/tmp/1"
+        ]
+    );
+
+    Ok(())
+}
+
+// Test that the most specific rule is applied, regardless of the order, and allow wins ties
+#[test]
+fn robots_filter_precedence() -> Result<(), Box<dyn std::error::Error>> {
+    let urls = [
+        "https://a.com/a",
+        "https://a.com/a/b",
+        "https://a.com/a/b/c",
+        "https://a.com/a/x",
+        "https://a.com/z",
+    ];
+
+    let allow_first = "User-agent: *\nAllow: /a/b\nDisallow: /a\n";
+    let disallow_first = "User-agent: *\nDisallow: /a\nAllow: /a/b\n";
+    for robots in [allow_first, disallow_first] {
+        assert_eq!(
+            robots_filter(&urls, &[("a.com", robots)])?,
+            ["a.com/a", "a.com/a/x"]
+        );
+    }
+
+    // longer disallow inside a shorter allow
+    let robots = "User-agent: *\nDisallow: /a/b\nAllow: /a\n";
+    assert_eq!(
+        robots_filter(&urls, &[("a.com", robots)])?,
+        ["a.com/a/b", "a.com/a/b/c"]
+    );
+
+    // same length, allow wins
+    let robots = "User-agent: *\nDisallow: /a/b\nAllow: /a/*\n";
+    assert_eq!(
+        robots_filter(&urls, &[("a.com", robots)])?,
+        Vec::<String>::new()
+    );
+
+    // rules of each agent are evaluated separately, any of them can disallow
+    let robots = [(
+        "a.com",
+        "User-agent: *\nDisallow: /a\n\nUser-agent: ccbot\nAllow: /a/b\nDisallow: /a/b/c\n",
+    )];
+    let disallowed = robots_filter(&urls, &robots)?;
+    assert_eq!(
+        disallowed,
+        ["a.com/a", "a.com/a/b", "a.com/a/b/c", "a.com/a/x"]
+    );
+    assert_eq!(
+        robots_annotate(&urls, &robots, &[])?,
+        expected_annotations(&urls, &disallowed)
+    );
+
+    Ok(())
+}
+
+// Test that urls beyond the in-memory limit are still evaluated with the allow rules
+#[test]
+fn robots_filter_max_disallowed() -> Result<(), Box<dyn std::error::Error>> {
+    let urls: Vec<String> = ["a", "a/b", "a/b/c", "a/b/d", "a/x", "a/y", "z"]
+        .iter()
+        .map(|path| format!("https://a.com/{path}"))
+        .collect();
+    let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+    let robots = [(
+        "a.com",
+        "User-agent: *\nDisallow: /a\nAllow: /a/b\nDisallow: /*/b/c\nDisallow: /a/*\n\n\
+         User-agent: ccbot\nDisallow: /a/b/d\n",
+    )];
+    let expected = [
+        "a.com/a",
+        "a.com/a/b/c",
+        "a.com/a/b/d",
+        "a.com/a/x",
+        "a.com/a/y",
+    ];
+    assert_eq!(robots_filter(&urls, &robots)?, expected);
+    for max_disallowed in ["0", "1", "2"] {
+        assert_eq!(
+            robots_filter_args(&urls, &robots, &["--max-disallowed", max_disallowed])?,
+            expected
+        );
+    }
+
+    Ok(())
+}
+
+// Test that wildcard and anchored patterns searched in the index match the same urls
+// as evaluating each url with the compiled robots.txt
+#[test]
+fn robots_filter_patterns() -> Result<(), Box<dyn std::error::Error>> {
+    let urls = [
+        "https://a.com/",
+        "https://a.com/a.php",
+        "https://a.com/a.php?x=1",
+        "https://a.com/aab",
+        "https://a.com/aaab",
+        "https://a.com/aaba",
+        "https://a.com/abc",
+        "https://a.com/axbxc",
+        "https://a.com/cba",
+        "https://a.com/x",
+        "https://a.com/x/y",
+        "https://a.com/y$",
+        "https://a.com/y$z",
+        "https://a.com/z/b/z",
+        "https://a.com:8080/x",
+        "https://a.comb/x",
+    ];
+    let rules = [
+        "/*.php$", "/*aab$", "/a*b*c", "/x$", "/y$z", "/z*$", "*b/", "/$",
+    ];
+    for rule in rules {
+        let robots = format!("User-agent: *\nDisallow: {rule}\n");
+        let robots = [("a.com", robots.as_str())];
+        let disallowed = robots_filter_args(&urls, &robots, &["--origin-fallback", "exact"])?;
+        assert_eq!(
+            robots_annotate(&urls, &robots, &["--origin-fallback", "exact"])?,
+            expected_annotations(&urls, &disallowed),
+            "rule {rule}"
+        );
+        assert!(!disallowed.is_empty(), "rule {rule}");
+    }
+
+    Ok(())
+}
+
+// Test that origins with rules that match too many urls are banned and reported
+#[test]
+fn robots_filter_banned() -> Result<(), Box<dyn std::error::Error>> {
+    let urls = [
+        "https://a.com/x/1",
+        "https://a.com/x/2",
+        "https://a.com/x/3",
+        "https://a.com/x/4",
+        "https://a.com/y",
+        "https://a.com/z",
+        "https://b.com/x/1",
+    ];
+    let robots = [
+        ("a.com", "User-agent: *\nDisallow: /x\nDisallow: /y\n"),
+        ("b.com", "User-agent: *\nDisallow: /x\n"),
+    ];
+    let report = NamedTempFile::new()?;
+    let report_path = report.path().to_str().unwrap();
+
+    // the rest of the rules of a banned origin are not applied
+    let disallowed = robots_filter_args(
+        &urls,
+        &robots,
+        &["--max-results", "2", "--banned-report", report_path],
+    )?;
+    assert!(!disallowed.contains(&String::from("a.com/y")));
+    assert!(disallowed.contains(&String::from("b.com/x/1")));
+    assert_eq!(fs::read_to_string(report.path())?, "https://a.com\t/x\t3\n");
+
+    // all the urls of a banned origin are disallowed
+    let disallowed =
+        robots_filter_args(&urls, &robots, &["--max-results", "2", "--disallow-banned"])?;
+    let keys: Vec<_> = urls.iter().map(|url| url.replace("https://", "")).collect();
+    assert_eq!(disallowed, keys);
+
+    // allow rules do not count to ban an origin
+    let robots = [(
+        "a.com",
+        "User-agent: *\nAllow: /\nDisallow: /y\nAllow: /x/1\nDisallow: /x/\n",
+    )];
+    let disallowed =
+        robots_filter_args(&urls, &robots, &["--max-results", "4", "--disallow-banned"])?;
+    assert_eq!(
+        disallowed,
+        ["a.com/x/2", "a.com/x/3", "a.com/x/4", "a.com/y"]
+    );
+
+    Ok(())
+}
+
+// Test that the disallowed urls written to an FST are the printed ones, sorted and unique
+#[test]
+fn robots_filter_output_fst() -> Result<(), Box<dyn std::error::Error>> {
+    let urls = [
+        "https://a.com/a",
+        "https://a.com/a/b",
+        "https://a.com/b",
+        "https://b.com/a",
+        "https://b.com/c",
+    ];
+    // the same robots.txt twice, so urls are disallowed more than once
+    let robots = [
+        ("a.com", "User-agent: *\nDisallow: /a\n"),
+        ("b.com", "User-agent: *\nDisallow: /\n"),
+        ("a.com", "User-agent: *\nDisallow: /a\n"),
+    ];
+    let tmp = tempdir()?;
+    let output = tmp.path().join("disallowed.fst");
+    let printed = robots_filter_args(&urls, &robots, &[])?;
+    let written = robots_filter_args(
+        &urls,
+        &robots,
+        &[
+            "--output-fst",
+            output.to_str().unwrap(),
+            "--sort-memory",
+            "100",
+        ],
+    )?;
+    assert!(written.is_empty());
+
+    let set = Set::new(fs::read(&output)?)?;
+    let mut expected = printed.clone();
+    expected.dedup();
+    assert_eq!(set.stream().into_strs()?, expected);
+    assert_eq!(expected, ["a.com/a", "a.com/a/b", "b.com/a", "b.com/c"]);
+    // only the output is left, the sorted runs are removed
+    assert_eq!(fs::read_dir(tmp.path())?.count(), 1);
+
+    Ok(())
+}
+
+// Test that malformed lines in the robots.txt file follow the bad record policy
+#[test]
+fn robots_filter_on_error() -> Result<(), Box<dyn std::error::Error>> {
+    let index = url_index(&["https://a.com/x", "https://b.com/x"])?;
+    let mut robots_file = robots_jsonl(&[("a.com", "User-agent: *\nDisallow: /x\n")])?;
+    writeln!(robots_file, "{{\"u\": \"https://c.com/robots.txt\"")?;
+    writeln!(
+        robots_file,
+        "{}",
+        serde_json::json!({"u": "https://b.com/robots.txt", "p": "User-agent: *\nDisallow: /\n"})
+    )?;
+    let filter = || -> Result<Command, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("robots-filter")?;
+        cmd.arg("--format")
+            .arg("robots")
+            .arg(index.path())
+            .arg(robots_file.path());
+        Ok(cmd)
+    };
+
+    assert_fails_with(&mut filter()?, ":2: ");
+
+    let expected = "a.com/x\nb.com/x\n";
+    let output = filter()?.arg("--on-error").arg("skip").output()?;
+    assert!(output.status.success());
+    let mut disallowed: Vec<_> = std::str::from_utf8(&output.stdout)?.lines().collect();
+    disallowed.sort();
+    assert_eq!(disallowed.join("\n") + "\n", expected);
+
+    let dir = tempdir()?;
+    let quarantine = dir.path().join("bad.jsonl.zst");
+    let output = filter()?
+        .arg("--on-error")
+        .arg("quarantine")
+        .arg("--quarantine-file")
+        .arg(&quarantine)
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(output.stdout.len(), expected.len());
+    assert_eq!(
+        zstd::decode_all(fs::File::open(&quarantine)?)?,
+        b"{\"u\": \"https://c.com/robots.txt\"\n"
+    );
+
+    Ok(())
+}
+
+// Test that urls evaluated with a compiled robots index give the same result as robots-filter
+#[test]
+fn robots_compile_annotate() -> Result<(), Box<dyn std::error::Error>> {
+    let urls = [
+        "http://a.com/a",
+        "http://a.com/a/b",
+        "http://a.com/a/b/c",
+        "http://a.com/a/x",
+        "http://a.com/a/x.html",
+        "http://a.com/z",
+        "https://b.com/x",
+    ];
+    let robots = [
+        (
+            "a.com",
+            "User-agent: *\nDisallow: /a\nAllow: /a/b\nDisallow: /*.html$\n",
+        ),
+        ("www.b.com", "User-agent: other\nDisallow: /\n"),
+    ];
+    let disallowed = robots_filter(&urls, &robots)?;
+    assert_eq!(
+        robots_annotate(&urls, &robots, &[])?,
+        expected_annotations(&urls, &disallowed)
+    );
+    assert_eq!(disallowed, ["a.com/a", "a.com/a/x", "a.com/a/x.html"]);
+
+    Ok(())
+}
+
+// Test that rules apply to their origin, and to the variants without robots.txt
+// only as allowed by the fallback policy
+#[test]
+fn robots_origin_fallback() -> Result<(), Box<dyn std::error::Error>> {
+    let urls = [
+        "http://a.com/x",
+        "http://www.a.com/x",
+        "https://a.com/x",
+        "https://a.com:8443/x",
+        "https://sub.a.com/x",
+        "https://www.a.com/x",
+    ];
+    // http://a.com has its own robots.txt that allows everything
+    let robots = [
+        ("https://a.com", "User-agent: *\nDisallow: /\n"),
+        ("http://a.com", "User-agent: *\nDisallow:\n"),
+    ];
+
+    // the disallowed lists have no scheme and 'www.', so the variants share the key
+    let cases: [(&str, &[&str], [bool; 6]); 4] = [
+        ("exact", &["a.com/x"], [true, true, false, true, true, true]),
+        (
+            "scheme",
+            &["a.com/x"],
+            [true, true, false, true, true, true],
+        ),
+        (
+            "www",
+            &["a.com/x", "a.com/x"],
+            [true, true, false, true, true, false],
+        ),
+        (
+            "any",
+            &["a.com/x", "a.com/x"],
+            [true, true, false, true, true, false],
+        ),
+    ];
+    for (fallback, disallowed, allowed) in cases {
+        let args = ["--origin-fallback", fallback];
+        assert_eq!(
+            robots_filter_args(&urls, &robots, &args)?,
+            disallowed,
+            "fallback {fallback}"
+        );
+        let expected: Vec<String> = allowed
+            .iter()
+            .map(|allowed| format!("{{\"allowed\": {allowed}}}"))
+            .collect();
+        assert_eq!(
+            robots_annotate(&urls, &robots, &args)?,
+            expected,
+            "fallback {fallback}"
+        );
+    }
+
+    Ok(())
+}
+
+// Test that documents are evaluated with the robots.txt in effect when they were crawled
+#[test]
+fn robots_time_policy() -> Result<(), Box<dyn std::error::Error>> {
+    // robots.txt of the same origin fetched 60 days apart
+    let mut robots_file = NamedTempFile::new()?;
+    for (ts, body) in [
+        ("2024-01-01T00:00:00Z", "User-agent: *\nDisallow: /a\n"),
+        ("2024-03-01T00:00:00Z", "User-agent: *\nDisallow: /b\n"),
+    ] {
+        let doc = serde_json::json!({"u": "https://a.com/robots.txt", "p": body, "ts": ts});
+        writeln!(robots_file, "{doc}")?;
+    }
+    // without fetch time, it applies to every document
+    let doc =
+        serde_json::json!({"u": "https://a.com/robots.txt", "p": "User-agent: *\nDisallow: /c\n"});
+    writeln!(robots_file, "{doc}")?;
+    let docs = [
+        ("https://a.com/a", Some("2024-01-10T12:00:00Z")),
+        ("https://a.com/b", Some("2024-01-10T12:00:00Z")),
+        ("https://a.com/a", Some("2024-02-25T08:30:00Z")),
+        ("https://a.com/b", Some("2024-02-25T08:30:00Z")),
+        ("https://a.com/a", None),
+        ("https://a.com/c", Some("2024-01-10T12:00:00Z")),
+    ];
+    let docs: Vec<_> = docs
+        .iter()
+        .map(|(u, ts)| match ts {
+            Some(ts) => serde_json::json!({"u": u, "ts": ts}),
+            None => serde_json::json!({ "u": u }),
+        })
+        .collect();
+
+    // documents without crawl time are evaluated with all the robots.txt
+    let cases: [(&[&str], [bool; 6]); 4] = [
+        (
+            &["--time-policy", "all"],
+            [false, false, false, false, false, false],
+        ),
+        (
+            &["--time-policy", "closest"],
+            [false, true, true, false, false, false],
+        ),
+        // both robots.txt are within 60 days of the documents
+        (
+            &["--time-policy", "window", "--time-window", "60"],
+            [false, false, false, false, false, false],
+        ),
+        // none in the window, the closest is used
+        (
+            &["--time-policy", "window", "--time-window", "1"],
+            [false, true, true, false, false, false],
+        ),
+    ];
+    for (args, expected) in cases {
+        let expected: Vec<String> = expected
+            .iter()
+            .map(|allowed| format!("{{\"allowed\": {allowed}}}"))
+            .collect();
+        assert_eq!(
+            annotate_docs(&robots_file, &docs, args)?,
+            expected,
+            "{args:?}"
+        );
+    }
+
+    Ok(())
+}
+
+// Test that the url index has the normalized urls sorted and unique,
+// whether they are sorted in memory or with temporary runs
+#[test]
+fn build_url_index() -> Result<(), Box<dyn std::error::Error>> {
+    let batches = [
+        ["HTTP://b.com/x", "https://a.com/%7e", "https://a.com/z"],
+        ["https://a.com/~", "http://b.com:80/x#f", "https://c.com"],
+    ];
+    let mut files = Vec::new();
+    for urls in batches {
+        let mut file = NamedTempFile::new()?;
+        for url in urls {
+            writeln!(file, "{}", serde_json::json!({"u": url, "text": "x"}))?;
+        }
+        files.push(file);
+    }
+    let expected = [
+        "http://b.com/x",
+        "https://a.com/z",
+        "https://a.com/~",
+        "https://c.com/",
+    ];
+
+    for sort_memory in ["1MiB", "100"] {
+        let tmp = tempdir()?;
+        let output = tmp.path().join("urls.fst");
+        Command::cargo_bin("build-url-index")?
+            .arg("--sort-memory")
+            .arg(sort_memory)
+            .arg(&output)
+            .args(files.iter().map(|file| file.path()))
+            .assert()
+            .success();
+
+        let set = Set::new(fs::read(&output)?)?;
+        assert_eq!(set.stream().into_strs()?, expected, "{sort_memory}");
+        assert_eq!(fs::read_dir(tmp.path())?.count(), 1);
+    }
+
+    Ok(())
+}
+
+// Test the normalization of urls for the url indexes
+#[test]
+fn normalize_urls() -> Result<(), Box<dyn std::error::Error>> {
+    let urls = [
+        (
+            "HTTP://WWW.Example.COM:80/a%7eb#fragment",
+            "http://www.example.com/a~b",
+        ),
+        ("https://example.com:443", "https://example.com/"),
+        (
+            "https://example.com:8443/x y?",
+            "https://example.com:8443/x%20y?",
+        ),
+        (
+            "http://user:pw@Bücher.de/ü?q=%2f",
+            "http://xn--bcher-kva.de/%C3%BC?q=%2F",
+        ),
+        ("münchen.de.", "http://xn--mnchen-3ya.de/"),
+        ("http://例え.jp/", "http://xn--r8jz45g.jp/"),
+    ];
+    let input: String = urls
+        .iter()
+        .map(|(url, _)| format!("{}\n", serde_json::json!({ "u": url })))
+        .collect();
+    let expected: String = urls.iter().map(|(_, key)| format!("{key}\n")).collect();
+
+    Command::cargo_bin("normalize-urls")?
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(expected);
+
+    Ok(())
+}
+
+// Test that annotations keep the input order with ids, also for bad records
+#[test]
+fn robotstxt_annotate_ids() -> Result<(), Box<dyn std::error::Error>> {
+    let robots_file = robots_jsonl(&[("a.com", "User-agent: *\nDisallow: /x\n")])?;
+    let mut docs: Vec<_> = (0..20)
+        .map(|i| {
+            let path = if i % 3 == 0 { "x" } else { "y" };
+            serde_json::json!({ "id": format!("doc{i}"), "u": format!("https://a.com/{path}/{i}") })
+        })
+        .collect();
+    // no url to annotate
+    docs.push(serde_json::json!({ "id": "nourl" }));
+
+    let mut expected: Vec<String> = (0..20)
+        .map(|i| format!("{{\"id\": \"doc{i}\", \"allowed\": {}}}", i % 3 != 0))
+        .collect();
+    expected.push(String::from("{\"id\": \"nourl\", \"allowed\": null}"));
+    assert_eq!(
+        annotate_docs(
+            &robots_file,
+            &docs,
+            &[
+                "--include-id",
+                "--batch-size",
+                "3",
+                "--threads",
+                "2",
+                "--on-error",
+                "skip"
+            ]
+        )?,
+        expected
+    );
+
+    Ok(())
+}
+
+// Test that a bad record with the fail policy does not leave an incomplete output
+#[test]
+fn robotstxt_annotate_fail() -> Result<(), Box<dyn std::error::Error>> {
+    let robots_file = robots_jsonl(&[("a.com", "User-agent: *\nDisallow: /x\n")])?;
+    let dir = tempdir()?;
+    let index = dir.path().join("robots.fst");
+    Command::cargo_bin("robots-compile")?
+        .arg(&index)
+        .arg(robots_file.path())
+        .assert()
+        .success();
+
+    let metadata = dir.path().join("metadata.jsonl");
+    fs::write(
+        &metadata,
+        "{\"u\": \"https://a.com/x\"}\n{\"id\": \"nourl\"}\n{\"u\": \"https://a.com/y\"}\n",
+    )?;
+    let annotations = dir.path().join("annotations.jsonl.zst");
+    assert_fails_with(
+        Command::cargo_bin("robotstxt-annotate")?
+            .arg("--robots-index")
+            .arg(&index)
+            .arg(&metadata)
+            .arg(&annotations),
+        ":2: ",
+    );
+    assert!(!annotations.exists());
+
+    Ok(())
+}
+
+// Write JSONL documents with the given texts to a file
+fn write_docs(path: &std::path::Path, texts: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = fs::File::create(path)?;
+    for (i, text) in texts.iter().enumerate() {
+        writeln!(file, "{}", serde_json::json!({ "id": i, "text": text }))?;
+    }
+    Ok(())
+}
+
+// Check that a command fails without output and with a message in the log
+fn assert_fails_with(cmd: &mut Command, message: &str) {
+    let assert = cmd.assert().failure().stdout("");
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.contains(message), "'{message}' not in: {stderr}");
+}
+
+// Test that dedup refuses to run when the input is not the one in the cluster file
+#[test]
+fn dedup_input_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let clusters = dir.path().join("clusters.zst");
+    fs::write(&clusters, "3\n0 1 0\n")?;
+    let docs = dir.path().join("docs.jsonl");
+    write_docs(&docs, &["a", "b"])?;
+
+    // counted before deduplicating
+    assert_fails_with(
+        Command::cargo_bin("dedup")?
+            .arg("--count-input")
+            .arg(&clusters)
+            .arg(&docs),
+        "Number of input docs is different than in cluster file: 2 vs 3",
+    );
+
+    // stdin can not be counted
+    assert_fails_with(
+        Command::cargo_bin("dedup")?
+            .arg("--count-input")
+            .arg(&clusters)
+            .pipe_stdin(&docs)?,
+        "when reading from stdin",
+    );
+
+    // the manifest written by mhindex next to the cluster file is used
+    let indexed = dir.path().join("indexed.jsonl");
+    write_docs(&indexed, &["a", "b", "c"])?;
+    let output = Command::cargo_bin("mhindex")?
+        .arg("--manifest")
+        .arg(dir.path().join("clusters.manifest.json"))
+        .arg(&indexed)
+        .output()?;
+    assert!(output.status.success());
+    fs::write(&clusters, output.stdout)?;
+    assert_fails_with(
+        Command::cargo_bin("dedup")?.arg(&clusters).arg(&docs),
+        "Input is not the same that was indexed",
+    );
+
+    Ok(())
+}
+
+// Test that multiple clusters arrays are merged and the cluster sizes are added
+#[test]
+fn dedup_cluster_arrays() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let docs = dir.path().join("docs.jsonl");
+    write_docs(&docs, &["a", "b", "c", "d", "e"])?;
+    let expected = "{\"id\":0,\"text\":\"a\",\"cluster_size\":2}\n\
+                    {\"id\":1,\"text\":\"b\",\"cluster_size\":2}\n\
+                    {\"id\":4,\"text\":\"e\",\"cluster_size\":1}\n";
+
+    // one array for each band
+    let clusters = dir.path().join("clusters");
+    fs::write(&clusters, "5\n0 1 0 3 4 \n5\n0 1 2 1 4 \n")?;
+    Command::cargo_bin("dedup")?
+        .arg("-c")
+        .arg(&clusters)
+        .arg(&docs)
+        .assert()
+        .success()
+        .stdout(expected);
+
+    // already merged in a single array
+    fs::write(&clusters, "5\n0 1 0 1 4 \n")?;
+    Command::cargo_bin("dedup")?
+        .arg("-c")
+        .arg(&clusters)
+        .arg(&docs)
+        .assert()
+        .success()
+        .stdout(expected);
+
+    // anything after the number of records in a header is ignored
+    fs::write(&clusters, "5 merged\t2 bands\n0 1 0 1 4 \n")?;
+    Command::cargo_bin("dedup")?
+        .arg("-c")
+        .arg(&clusters)
+        .arg(&docs)
+        .assert()
+        .success()
+        .stdout(expected);
+
+    // cluster sizes of a previous run are replaced
+    fs::write(&docs, expected)?;
+    fs::write(&clusters, "3\n0 0 2 \n")?;
+    Command::cargo_bin("dedup")?
+        .arg("-c")
+        .arg(&clusters)
+        .arg(&docs)
+        .assert()
+        .success()
+        .stdout(
+            "{\"id\":0,\"text\":\"a\",\"cluster_size\":2}\n\
+             {\"id\":4,\"text\":\"e\",\"cluster_size\":1}\n",
+        );
+
+    // arrays that do not match the header
+    fs::write(&clusters, "5\n0 1 0 3 4 \n4\n0 1 2 1 \n")?;
+    Command::cargo_bin("dedup")?
+        .arg(&clusters)
+        .arg(&docs)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+// Test that inputs in zstd, gzip and plain text, given as directories and globs,
+// are read in order by multiple readers
+#[test]
+fn dedup_mixed_inputs() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    fs::create_dir(dir.path().join("data"))?;
+    let lines: Vec<String> = (0..40)
+        .map(|i| serde_json::json!({ "id": i, "text": format!("text {i}") }).to_string() + "\n")
+        .collect();
+    // the format is detected by content, the names are only used for ordering
+    let mut parts = Vec::new();
+    for (i, chunk) in lines.chunks(7).enumerate() {
+        let text = chunk.concat();
+        let (name, data) = match i % 3 {
+            0 => (format!("data/{i}.jsonl.zst"), compress(text.as_bytes(), 0)?),
+            1 => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(text.as_bytes())?;
+                (format!("data/{i}.jsonl"), encoder.finish()?)
+            }
+            _ => (format!("{i}.part.jsonl"), text.clone().into_bytes()),
+        };
+        fs::write(dir.path().join(&name), data)?;
+        parts.push((!name.starts_with("data/"), name, text));
+    }
+    // a file that is not an input in the directory
+    fs::write(dir.path().join("data/README"), "not jsonl\n")?;
+    let ids: Vec<String> = (0..lines.len()).map(|i| i.to_string()).collect();
+    let clusters = dir.path().join("clusters");
+    fs::write(&clusters, format!("{}\n{} \n", lines.len(), ids.join(" ")))?;
+
+    // documents in the directory sorted by name, followed by the globbed ones
+    parts.sort();
+    let expected: String = parts.into_iter().map(|(_, _, text)| text).collect();
+    Command::cargo_bin("dedup")?
+        .arg("--reader-threads")
+        .arg("3")
+        .arg(&clusters)
+        .arg(dir.path().join("data"))
+        .arg(dir.path().join("*.part.jsonl"))
+        .assert()
+        .success()
+        .stdout(expected);
+
+    Ok(())
+}
+
+// Number of documents of each split listed in the manifest of an output
+fn manifest_docs(path: &std::path::Path) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let manifest: serde_json::Value = serde_json::from_slice(&fs::read(path)?)?;
+    assert_eq!(manifest["complete"], true);
+    let mut num_docs = Vec::new();
+    for split in manifest["splits"].as_array().unwrap() {
+        let docs = zstd::decode_all(fs::File::open(split["path"].as_str().unwrap())?)?;
+        let count = docs.iter().filter(|b| **b == b'\n').count() as u64;
+        assert_eq!(split["num_docs"], count);
+        num_docs.push(count);
+    }
+    Ok(num_docs)
+}
+
+// Test that --split-docs limits the documents of each split and they are listed in the manifest
+#[test]
+fn split_docs_manifest() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let docs = dir.path().join("docs.jsonl");
+    let texts: Vec<String> = (0..10).map(|i| format!("text {}", i % 8)).collect();
+    let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+    write_docs(&docs, &texts)?;
+    let clusters = dir.path().join("clusters");
+    fs::write(&clusters, "10\n0 1 2 3 4 5 6 7 0 1 \n")?;
+
+    Command::cargo_bin("dedup")?
+        .arg("--split-docs")
+        .arg("3")
+        .arg("-o")
+        .arg(dir.path().join("dedup_{N}.jsonl.zst"))
+        .arg(&clusters)
+        .arg(&docs)
+        .assert()
+        .success();
+    assert_eq!(
+        manifest_docs(&dir.path().join("dedup.manifest.json"))?,
+        [3, 3, 2]
+    );
+
+    Command::cargo_bin("exact-dedup")?
+        .args(["--split-docs", "5", "-n", "1000", "-t", "1", "-b", "1024"])
+        .arg(dir.path().join("exact_{N}.jsonl.zst"))
+        .arg(&docs)
+        .assert()
+        .success();
+    assert_eq!(
+        manifest_docs(&dir.path().join("exact.manifest.json"))?,
+        [5, 3]
+    );
+
+    Ok(())
+}
+
+// Run a command that writes splits with a checkpoint, without interruption and
+// interrupted by a bad record, then resumed once the record is fixed.
+// `args` go before the output and input, so they can end with the option of the output.
+// Check that the resumed output is byte-identical to the uninterrupted one.
+fn check_resume(
+    command: &str,
+    args: &[&str],
+    lines: &[&str],
+    bad_line: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let input = dir.path().join("input.jsonl");
+    let run = |name: &str| -> Result<Command, Box<dyn std::error::Error>> {
+        let out_dir = dir.path().join(name);
+        fs::create_dir_all(&out_dir)?;
+        let mut cmd = Command::cargo_bin(command)?;
+        cmd.arg("--checkpoint")
+            .arg(out_dir.join("checkpoint.json"))
+            .args(args)
+            .arg(out_dir.join("batch_{N}.jsonl.zst"))
+            .arg(&input);
+        Ok(cmd)
+    };
+    let read_splits = |name: &str| -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        let mut splits = Vec::new();
+        for idx in 1.. {
+            match fs::read(dir.path().join(name).join(format!("batch_{idx}.jsonl.zst"))) {
+                Ok(split) => splits.push(split),
+                Err(_) => break,
+            }
+        }
+        Ok(splits)
+    };
+    fs::write(&input, lines.join("\n") + "\n")?;
+    run("full")?.assert().success();
+
+    let mut broken = lines.to_vec();
+    broken[bad_line] = "{\"text\": \u{ff}";
+    fs::write(&input, broken.join("\n") + "\n")?;
+    run("resumed")?.assert().failure();
+    assert!(dir.path().join("resumed/checkpoint.json").exists());
+    let interrupted = read_splits("resumed")?;
+    assert!(!interrupted.is_empty());
+
+    fs::write(&input, lines.join("\n") + "\n")?;
+    run("resumed")?.assert().success();
+    assert!(!dir.path().join("resumed/checkpoint.json").exists());
+    let full = read_splits("full")?;
+    assert!(full.len() > interrupted.len());
+    assert_eq!(read_splits("resumed")?, full);
+    Ok(())
+}
+
+// Test that dedup and exact-dedup resumed from a checkpoint give the same output
+#[test]
+fn checkpoint_resume() -> Result<(), Box<dyn std::error::Error>> {
+    let lines: Vec<String> = (0..10)
+        .map(|i| serde_json::json!({ "id": i, "text": format!("text {}", i % 7) }).to_string())
+        .collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    let dir = tempdir()?;
+    let clusters = dir.path().join("clusters");
+    fs::write(&clusters, "10\n0 1 2 3 4 5 6 0 1 9 \n")?;
+
+    let clusters = clusters.to_str().unwrap();
+    check_resume(
+        "dedup",
+        &["--split-docs", "2", "-c", clusters, "-o"],
+        &lines,
+        6,
+    )?;
+    check_resume(
+        "exact-dedup",
+        &["--split-docs", "2", "-n", "1000", "-t", "1", "-b", "1024"],
+        &lines,
+        6,
+    )?;
+    Ok(())
+}